@@ -0,0 +1,72 @@
+#![no_main]
+
+use borsh::BorshSerialize;
+use ico::{process_instruction, ICOAccount, CURRENT_VERSION, ICO_SEED};
+use libfuzzer_sys::fuzz_target;
+use solana_program::{account_info::AccountInfo, pubkey::Pubkey, system_program};
+
+// Feeds arbitrary instruction data to the program against an initialized ICO
+// account. Any input is allowed to fail, but only by returning a
+// `ProgramError`; a panic is a bug in the decoder.
+fuzz_target!(|instruction_data: &[u8]| {
+    let program_id = Pubkey::new_unique();
+    let admin_key = Pubkey::new_unique();
+    let (ico_key, ico_bump) =
+        Pubkey::find_program_address(&[ICO_SEED, admin_key.as_ref()], &program_id);
+    let participant_key = Pubkey::new_unique();
+
+    let mut ico_state = ICOAccount {
+        version: CURRENT_VERSION,
+        admin: admin_key,
+        total_supply: 10000,
+        pre_sale_supply: 3000,
+        sale_supply: 7000,
+        price_denominator: 1,
+        ico_bump,
+        ..ICOAccount::default()
+    };
+    ico_state.balance.push((admin_key, 10000));
+
+    let mut ico_data = ico_state.try_to_vec().unwrap();
+    ico_data.resize(ico_data.len() + 1024, 0);
+    let mut ico_lamports = 1_000_000_000;
+    let mut admin_lamports = 1_000_000_000;
+    let mut participant_lamports = 1_000_000_000;
+    let mut admin_data = vec![0; 8];
+    let mut participant_data = vec![0; 8];
+
+    let accounts = [
+        AccountInfo::new(
+            &ico_key,
+            false,
+            true,
+            &mut ico_lamports,
+            &mut ico_data,
+            &program_id,
+            false,
+            0,
+        ),
+        AccountInfo::new(
+            &admin_key,
+            true,
+            true,
+            &mut admin_lamports,
+            &mut admin_data,
+            &system_program::ID,
+            false,
+            0,
+        ),
+        AccountInfo::new(
+            &participant_key,
+            true,
+            true,
+            &mut participant_lamports,
+            &mut participant_data,
+            &program_id,
+            false,
+            0,
+        ),
+    ];
+
+    let _ = process_instruction(&program_id, &accounts, instruction_data);
+});
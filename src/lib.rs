@@ -2,16 +2,102 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     clock::Clock,
-    entrypoint,
+    ed25519_program, entrypoint,
     entrypoint::ProgramResult,
+    hash::{hashv, Hasher},
     msg,
+    program::{invoke_signed, set_return_data},
     program_error::ProgramError,
     pubkey::Pubkey,
-    sysvar::Sysvar,
+    rent::Rent,
+    system_instruction,
+    sysvar::{instructions, Sysvar},
 };
 
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
+/// Layout version of `ICOAccount`. Every change to the serialized layout bumps
+/// it, keeps a snapshot of the previous layout (`ICOAccountV1`,
+/// `ICOAccountV2`, ...) and teaches `migrate_state` to convert from it.
+pub const CURRENT_VERSION: u8 = 3;
+
+/// Upper bound on `top_holders` so the sort stays cheap and the result fits in
+/// return data.
+pub const MAX_TOP_HOLDERS: usize = 20;
+
+pub const HEALTH_SUPPLY_MISMATCH: u8 = 1 << 0;
+pub const HEALTH_RECORDS_EXCEED_BALANCES: u8 = 1 << 1;
+pub const HEALTH_PROCEEDS_MISMATCH: u8 = 1 << 2;
+pub const HEALTH_BALANCES_UNSORTED: u8 = 1 << 3;
+
+/// Mint payload length when it carries a vesting schedule:
+/// discriminant, amount, start, cliff and end.
+pub const MINT_WITH_VESTING_LEN: usize = 33;
+
+pub const BPS_DENOMINATOR: u64 = 10000;
+
+/// Delay between queueing a timelocked admin action and being able to execute
+/// it.
+pub const TIMELOCK_DELAY: u64 = 24 * 60 * 60;
+
+/// Most sale rounds, the original sale window included. Every `extend_sale`
+/// opens another round.
+pub const MAX_ROUNDS: u64 = 4;
+
+/// Size limit the runtime puts on `set_return_data`.
+pub const MAX_RETURN_DATA_LEN: usize = 1024;
+
+/// `ReturnEnvelope` kinds, one per view that returns an envelope.
+pub const RETURN_REMAINING_ALLOCATION: u8 = 1;
+pub const RETURN_SUPPLY_INFO: u8 = 2;
+pub const RETURN_TOP_HOLDERS: u8 = 3;
+pub const RETURN_WHITELIST_PAGE: u8 = 4;
+pub const RETURN_BUDGET_QUOTE: u8 = 5;
+pub const RETURN_ACTIVE_LIMIT: u8 = 6;
+pub const RETURN_BALANCE_RECONCILIATION: u8 = 7;
+
+/// Most whitelisted pubkeys that fit in one page: the return data minus the
+/// envelope kind and the two Borsh length prefixes.
+pub const MAX_WHITELIST_PAGE: usize = (MAX_RETURN_DATA_LEN - 1 - 4 - 4) / 32;
+
+/// Presale whitelist tiers. OG buyers pay `og_pre_sale_price` and get
+/// `og_allocation` when those are set.
+pub const TIER_REGULAR: u8 = 0;
+pub const TIER_OG: u8 = 1;
+
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+pub const CONFIG_VERSION: u8 = 2;
+
+/// Version tag of the structured event logs, see `log_purchase_event`.
+pub const EVENT_VERSION: u8 = 1;
+
+/// Extra space on top of the current serialized state that clients should
+/// reserve, so the account doesn't need a realloc for every new participant.
+pub const ACCOUNT_SPACE_HEADROOM: usize = 1024;
+
+/// Seed prefix of the ICO state PDA, derived as `[ICO_SEED, admin]`.
+pub const ICO_SEED: &[u8] = b"ico";
+
+/// Number of balance snapshots kept; the oldest is dropped once full.
+pub const MAX_BALANCE_SNAPSHOTS: usize = 16;
+
+/// Most empty balance entries `register_balance` lets exist at once, since
+/// anyone can call it for their own key. Entries stop counting once they hold
+/// tokens, so the number of holders isn't bounded by it.
+pub const MAX_REGISTERED_BALANCES: usize = 256;
+
+/// Most commitments pending at once, since any buyer can add one.
+pub const MAX_PURCHASE_COMMITMENTS: usize = 256;
+
+pub const IDEMPOTENCY_KEY_LEN: usize = 16;
+pub const IDEMPOTENCY_KEY_SLOTS: usize = 8;
+
+/// Upper bound on the items a single instruction may carry in a
+/// variable-length list, checked before anything is decoded.
+pub const MAX_BATCH_ITEMS: usize = 64;
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default)]
 pub struct ICOAccount {
+    pub version: u8,
     pub total_supply: u64,
     pub admin: Pubkey,
     pub balance: Vec<(Pubkey, u64)>,
@@ -24,10 +110,123 @@ pub struct ICOAccount {
     pub total_price_earned: u64,
     pub pre_sale_account: Vec<PreSaleAccount>,
     pub sale_account: Vec<SaleAccount>,
+    pub rounding_mode: RoundingMode,
+    pub paused: bool,
+    pub max_buy_per_tx: u64,
+    pub balance_commitment: [u8; 32],
+    pub whitelister: Pubkey,
+    pub cancelled: bool,
+    pub blocklist: Vec<Pubkey>,
+    pub idempotency_keys: [[u8; IDEMPOTENCY_KEY_LEN]; IDEMPOTENCY_KEY_SLOTS],
+    pub idempotency_cursor: u8,
+    pub pre_sale_supply: u64,
+    pub sale_supply: u64,
+    pub price_denominator: u64,
+    pub whitelist_requirement: WhitelistRequirement,
+    pub whitelist_mode: WhitelistMode,
+    pub whitelist_root: [u8; 32],
+    pub airdrop_vesting: Vec<VestingSchedule>,
+    pub refund_fee_bps: u64,
+    pub refund_fees_collected: u64,
+    pub early_bird_bonus_bps: u64,
+    pub early_bird_deadline: u64,
+    pub kyc_threshold: u64,
+    pub max_contribution: u64,
+    pub contributions: Vec<(Pubkey, u64)>,
+    pub allowed_amounts: Vec<u64>,
+    pub pending_action: Option<PendingAction>,
+    pub pre_sale_start_time: u64,
+    pub sold_out: bool,
+    pub treasury_split: Vec<(Pubkey, u16)>,
+    pub refund_cursor: u64,
+    pub min_presale_discount_bps: u64,
+    pub ico_bump: u8,
+    pub cooling_off_seconds: u64,
+    pub early_refund_penalty_bps: u64,
+    pub early_refund_penalties_collected: u64,
+    pub first_purchase_times: Vec<(Pubkey, u64)>,
+    pub bonus_tokens: Vec<(Pubkey, u64)>,
+    pub balance_snapshots: Vec<(u64, [u8; 32])>,
+    pub require_balance_registration: bool,
+    pub total_purchases: u64,
+    pub total_mints: u64,
+    pub total_whitelists: u64,
+    pub soft_cap: u64,
+    pub soft_cap_refunds: Vec<(Pubkey, u64)>,
+    pub commit_deadline: u64,
+    pub purchase_commitments: Vec<(Pubkey, [u8; 32])>,
+    pub max_presale_entries: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub transfers_enabled: bool,
+    pub transfers_to_admin: Vec<(Pubkey, u64)>,
+    pub og_pre_sale_price: u64,
+    pub og_allocation: u64,
+    pub terms_hash: [u8; 32],
+    pub total_withdrawn: u64,
+    pub preview_mode: bool,
+    pub preview_reservations: Vec<(Pubkey, u64)>,
+    pub hard_cap: u64,
+    pub treasury: Pubkey,
+    pub pending_treasury: Pubkey,
+    /// Off-chain key whose ed25519 signatures whitelist presale buyers in
+    /// `WhitelistMode::Signature`.
+    pub whitelist_authority: Pubkey,
+    /// Persisted while an instruction runs so a CPI back into the program can
+    /// be rejected.
+    pub locked: bool,
+    /// Shortfall in lamports a purchase may have before it is scaled down to
+    /// what the buyer can pay. Zero turns the grace off.
+    pub lamport_tolerance: u64,
+    /// When each participant first interacted with the ICO, by registering a
+    /// balance entry or buying.
+    pub first_seen: Vec<(Pubkey, u64)>,
+    /// Minimum time since `first_seen` before a presale purchase is allowed.
+    /// Zero disables the check.
+    pub min_account_age: u64,
+    /// Emergency key that may only pause and unpause purchases.
+    pub guardian: Pubkey,
+    /// Share of every token transfer, in basis points, credited to the
+    /// treasury, or to the admin while no treasury is set.
+    pub transfer_tax_bps: u64,
+    /// Unix time after which vested airdrop tokens can no longer be claimed and
+    /// the rest can be swept to the treasury. Zero never expires.
+    pub claim_deadline: u64,
+    /// Unix time before which the admin can't transfer or burn its own tokens.
+    /// Purchases still debit the admin pool. Can only be moved later.
+    pub admin_lockup_until: u64,
+    /// Time after a presale purchase during which the buyer can't transfer
+    /// their presale tokens. Zero disables the lock.
+    pub flip_lock_seconds: u64,
+    /// Time of every presale buyer's latest presale purchase.
+    pub presale_purchase_times: Vec<(Pubkey, u64)>,
+    /// Unsold tokens burned without shrinking `total_supply`. They still count
+    /// toward it, so the balances plus this sum to the supply.
+    pub burned_unsold: u64,
+    /// Sale rounds opened by `extend_sale` after the original window.
+    pub sale_extensions: u64,
 }
 
+/// Layout written by the first release of the program, before `version`,
+/// `paused`, `rounding_mode` and presale allocations existed. Only used to
+/// read accounts that still need to be migrated.
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
-pub struct PreSaleAccount {
+pub struct ICOAccountV1 {
+    pub total_supply: u64,
+    pub admin: Pubkey,
+    pub balance: Vec<(Pubkey, u64)>,
+    pub pre_sale_price: u64,
+    pub pre_sale_limit: u64,
+    pub sale_price: u64,
+    pub sale_limit: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub total_price_earned: u64,
+    pub pre_sale_account: Vec<PreSaleAccountV1>,
+    pub sale_account: Vec<SaleAccountV1>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct PreSaleAccountV1 {
     pub address: Pubkey,
     pub token_amount: u64,
     pub token_price: u64,
@@ -35,234 +234,8904 @@ pub struct PreSaleAccount {
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
-pub struct SaleAccount {
+pub struct SaleAccountV1 {
     pub address: Pubkey,
     pub token_amount: u64,
     pub token_price: u64,
 }
 
-impl PreSaleAccount {
-    pub fn whitelist(&mut self) {
-        self.whitelist_account = !self.whitelist_account;
-    }
+/// Layout of version 2, which added `version`, `rounding_mode`, `paused` and
+/// presale allocations. Only used to read accounts that still need to be
+/// migrated.
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct ICOAccountV2 {
+    pub version: u8,
+    pub total_supply: u64,
+    pub admin: Pubkey,
+    pub balance: Vec<(Pubkey, u64)>,
+    pub pre_sale_price: u64,
+    pub pre_sale_limit: u64,
+    pub sale_price: u64,
+    pub sale_limit: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub total_price_earned: u64,
+    pub pre_sale_account: Vec<PreSaleAccountV2>,
+    pub sale_account: Vec<SaleAccountV1>,
+    pub rounding_mode: RoundingMode,
+    pub paused: bool,
 }
 
-entrypoint!(process_instruction);
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct PreSaleAccountV2 {
+    pub address: Pubkey,
+    pub token_amount: u64,
+    pub token_price: u64,
+    pub whitelist_account: bool,
+    pub allocation: u64,
+}
 
-pub fn process_instruction(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-    instruction_data: &[u8],
-) -> ProgramResult {
-    msg!("ICO Program Enter Point");
+/// Direction used when a cost calculation does not divide evenly. `Ceil` is
+/// the default so fractional lamports are always charged to the buyer and the
+/// treasury can never be shorted.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    Floor,
+    #[default]
+    Ceil,
+}
 
-    let account_iter = &mut accounts.iter();
-    let ico_accounts = next_account_info(account_iter)?;
+/// Which phases only accept whitelisted buyers. Defaults to the presale only.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum WhitelistRequirement {
+    None,
+    #[default]
+    PreSaleOnly,
+    SaleOnly,
+    Both,
+}
 
-    if ico_accounts.owner != program_id {
-        msg!("ICO account does not have correct program id");
-        return Err(ProgramError::IncorrectProgramId);
+impl WhitelistRequirement {
+    pub fn pre_sale(self) -> bool {
+        matches!(
+            self,
+            WhitelistRequirement::PreSaleOnly | WhitelistRequirement::Both
+        )
+    }
+
+    pub fn sale(self) -> bool {
+        matches!(
+            self,
+            WhitelistRequirement::SaleOnly | WhitelistRequirement::Both
+        )
     }
+}
 
-    let mut ico_state = ICOAccount::try_from_slice(&ico_accounts.data.borrow())?;
+/// How presale buyers prove they are whitelisted: by an entry in
+/// `pre_sale_account`, by a Merkle proof of `(buyer, allocation)` against
+/// `whitelist_root`, or by an ed25519 signature over `(buyer, allocation)`
+/// from `whitelist_authority`.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum WhitelistMode {
+    #[default]
+    List,
+    Merkle,
+    Signature,
+}
 
-    match instruction_data[0] {
-        0 => {
-            intialize_ico(program_id, &mut ico_state, account_iter)?;
-        }
-        1 => {
-            let recipient_account_info = next_account_info(account_iter)?;
-            let amount_bytes = instruction_data[1..9].try_into().unwrap();
-            let amount = u64::from_le_bytes(amount_bytes);
-            mint_tokens(&mut ico_state, &recipient_account_info.key, amount)?;
+/// What happens to a new presale registration once `max_presale_entries` is
+/// reached: reject it, or evict the oldest entry that hasn't bought anything.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    #[default]
+    Reject,
+    EvictOldest,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, Copy, PartialEq)]
+pub enum SalePhase {
+    NotStarted,
+    PreSale,
+    Sale,
+    Ended,
+}
+
+/// Admin changes that have to go through the timelock.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, PartialEq)]
+pub enum TimelockAction {
+    SetPreSalePrice(u64),
+    SetSalePrice(u64),
+    IncreaseSupply(u64),
+    SetPriceDenominator(u64),
+    SetOgPreSalePrice(u64),
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, PartialEq)]
+pub struct PendingAction {
+    pub action: TimelockAction,
+    pub eta: u64,
+}
+
+/// Launch parameters of an ICO, validated by `intialize_ico` and by the
+/// dry-run instruction.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, PartialEq)]
+pub struct InitParams {
+    pub total_supply: u64,
+    pub pre_sale_price: u64,
+    pub pre_sale_limit: u64,
+    pub sale_price: u64,
+    pub sale_limit: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub pre_sale_supply: u64,
+    pub sale_supply: u64,
+}
+
+/// First initialization check a set of `InitParams` fails, returned by the
+/// dry run as its code; 0 means every check passed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitCheck {
+    ZeroSupply = 1,
+    ZeroPrice,
+    PhaseSupplyExceedsTotal,
+    InvalidSaleWindow,
+    EmptyPhaseSupply,
+}
+
+impl InitParams {
+    pub fn validate(&self) -> Result<(), InitCheck> {
+        if self.total_supply == 0 {
+            return Err(InitCheck::ZeroSupply);
         }
-        2 => {
-            pre_sale(&mut ico_state, accounts)?;
+        if self.pre_sale_price == 0 || self.sale_price == 0 {
+            return Err(InitCheck::ZeroPrice);
         }
-        3 => {
-            sale(&mut ico_state, accounts)?;
+        match self.pre_sale_supply.checked_add(self.sale_supply) {
+            // Empty pools would leave the ICO sold out before its first sale.
+            Some(0) => return Err(InitCheck::EmptyPhaseSupply),
+            Some(phase_supply) if phase_supply <= self.total_supply => {}
+            _ => return Err(InitCheck::PhaseSupplyExceedsTotal),
         }
-        4 => {
-            let account_to_whitelist_info = next_account_info(account_iter)?;
-            whitelist_account(&mut ico_state, &account_to_whitelist_info.key)?;
+        if self.sale_end_time <= self.sale_start_time {
+            return Err(InitCheck::InvalidSaleWindow);
         }
-        _ => return Err(ProgramError::InvalidInstructionData),
+        Ok(())
     }
+}
 
-    ico_state.serialize(&mut &mut ico_accounts.data.borrow_mut()[..])?;
+/// Versioned export of the configuration and balances for off-chain indexers.
+/// `balances` is sorted by pubkey.
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct ICOSnapshot {
+    pub snapshot_version: u8,
+    pub account_version: u8,
+    pub admin: Pubkey,
+    pub total_supply: u64,
+    pub pre_sale_price: u64,
+    pub sale_price: u64,
+    pub price_denominator: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub total_price_earned: u64,
+    pub balances: Vec<(Pubkey, u64)>,
+}
 
-    Ok(())
+/// Return data of view instructions, so clients can dispatch on `kind`
+/// before decoding `payload`.
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct ReturnEnvelope {
+    pub kind: u8,
+    pub payload: Vec<u8>,
 }
 
-pub fn intialize_ico(
-    program_id: &Pubkey,
-    ico_state: &mut ICOAccount,
-    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
-) -> ProgramResult {
-    let admin_account = next_account_info(account_iter)?;
+impl ReturnEnvelope {
+    pub fn set(kind: u8, payload: Vec<u8>) -> ProgramResult {
+        set_return_data(&ReturnEnvelope { kind, payload }.try_to_vec()?);
+        Ok(())
+    }
+}
 
-    if admin_account.key != program_id {
-        msg!("Caller is not the admin");
-        return Err(ProgramError::InvalidAccountData);
+/// Layout of the configuration export while `CONFIG_VERSION` was 1, kept for
+/// clients decoding older exports.
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct ICOConfigV1 {
+    pub config_version: u8,
+    pub account_version: u8,
+    pub admin: Pubkey,
+    pub whitelister: Pubkey,
+    pub treasury: Pubkey,
+    pub total_supply: u64,
+    pub pre_sale_price: u64,
+    pub og_pre_sale_price: u64,
+    pub pre_sale_limit: u64,
+    pub sale_price: u64,
+    pub sale_limit: u64,
+    pub price_denominator: u64,
+    pub rounding_mode: RoundingMode,
+    pub pre_sale_start_time: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub pre_sale_supply: u64,
+    pub sale_supply: u64,
+    pub max_buy_per_tx: u64,
+    pub max_contribution: u64,
+    pub kyc_threshold: u64,
+    pub whitelist_requirement: WhitelistRequirement,
+    pub whitelist_mode: WhitelistMode,
+    pub whitelist_root: [u8; 32],
+    pub max_presale_entries: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub og_allocation: u64,
+    pub refund_fee_bps: u64,
+    pub early_bird_bonus_bps: u64,
+    pub early_bird_deadline: u64,
+    pub min_presale_discount_bps: u64,
+    pub cooling_off_seconds: u64,
+    pub early_refund_penalty_bps: u64,
+    pub soft_cap: u64,
+    pub hard_cap: u64,
+    pub commit_deadline: u64,
+    pub terms_hash: [u8; 32],
+    pub paused: bool,
+    pub cancelled: bool,
+    pub sold_out: bool,
+    pub transfers_enabled: bool,
+    pub preview_mode: bool,
+    pub require_balance_registration: bool,
+}
+
+/// Every scalar configuration field of `ICOAccount`, for SDK code generation.
+/// New fields go into a new struct under a bumped `CONFIG_VERSION`, so every
+/// version keeps its layout.
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
+pub struct ICOConfigV2 {
+    pub config_version: u8,
+    pub account_version: u8,
+    pub admin: Pubkey,
+    pub whitelister: Pubkey,
+    pub treasury: Pubkey,
+    pub total_supply: u64,
+    pub pre_sale_price: u64,
+    pub og_pre_sale_price: u64,
+    pub pre_sale_limit: u64,
+    pub sale_price: u64,
+    pub sale_limit: u64,
+    pub price_denominator: u64,
+    pub rounding_mode: RoundingMode,
+    pub pre_sale_start_time: u64,
+    pub sale_start_time: u64,
+    pub sale_end_time: u64,
+    pub pre_sale_supply: u64,
+    pub sale_supply: u64,
+    pub max_buy_per_tx: u64,
+    pub max_contribution: u64,
+    pub kyc_threshold: u64,
+    pub whitelist_requirement: WhitelistRequirement,
+    pub whitelist_mode: WhitelistMode,
+    pub whitelist_root: [u8; 32],
+    pub max_presale_entries: u64,
+    pub eviction_policy: EvictionPolicy,
+    pub og_allocation: u64,
+    pub refund_fee_bps: u64,
+    pub early_bird_bonus_bps: u64,
+    pub early_bird_deadline: u64,
+    pub min_presale_discount_bps: u64,
+    pub cooling_off_seconds: u64,
+    pub early_refund_penalty_bps: u64,
+    pub soft_cap: u64,
+    pub hard_cap: u64,
+    pub commit_deadline: u64,
+    pub terms_hash: [u8; 32],
+    pub paused: bool,
+    pub cancelled: bool,
+    pub sold_out: bool,
+    pub transfers_enabled: bool,
+    pub preview_mode: bool,
+    pub require_balance_registration: bool,
+    pub whitelist_authority: Pubkey,
+    pub lamport_tolerance: u64,
+    pub min_account_age: u64,
+    pub guardian: Pubkey,
+    pub transfer_tax_bps: u64,
+    pub claim_deadline: u64,
+    pub admin_lockup_until: u64,
+    pub flip_lock_seconds: u64,
+}
+
+/// Linear vesting of `total_amount` between `start_time` and `end_time`, with
+/// nothing claimable before `cliff_time`. Only used for minted (airdropped)
+/// tokens, which are kept apart from purchased tokens.
+#[derive(BorshDeserialize, BorshSerialize, Debug, Clone, PartialEq)]
+pub struct VestingSchedule {
+    pub beneficiary: Pubkey,
+    pub total_amount: u64,
+    pub claimed_amount: u64,
+    pub start_time: u64,
+    pub cliff_time: u64,
+    pub end_time: u64,
+}
+
+impl VestingSchedule {
+    pub fn vested_amount(&self, current_time: u64) -> Result<u64, ProgramError> {
+        if current_time < self.cliff_time {
+            return Ok(0);
+        }
+        if current_time >= self.end_time {
+            return Ok(self.total_amount);
+        }
+        mul_div(
+            self.total_amount,
+            current_time - self.start_time,
+            self.end_time - self.start_time,
+            RoundingMode::Floor,
+        )
     }
 
-    ico_state.admin = *admin_account.key;
-    ico_state.total_supply = 10000;
-    ico_state.pre_sale_price = 100;
-    ico_state.pre_sale_limit = 50;
-    ico_state.sale_price = 200;
-    ico_state.sale_limit = 100;
-    ico_state.sale_start_time = 0;
-    ico_state.sale_end_time = 100;
-    ico_state
-        .balance
-        .push((*admin_account.key, ico_state.total_supply));
-    Ok(())
+    pub fn claimable_amount(&self, current_time: u64) -> Result<u64, ProgramError> {
+        Ok(self
+            .vested_amount(current_time)?
+            .saturating_sub(self.claimed_amount))
+    }
+
+    /// Next vesting milestone after `current_time`: the cliff, then the end
+    /// of the schedule, or `None` once everything has vested.
+    pub fn next_unlock_time(&self, current_time: u64) -> Option<u64> {
+        if current_time < self.cliff_time {
+            Some(self.cliff_time)
+        } else if current_time < self.end_time {
+            Some(self.end_time)
+        } else {
+            None
+        }
+    }
 }
 
-pub fn mint_tokens(
-    ico_state: &mut ICOAccount,
-    recipient_accounts: &Pubkey,
-    amount: u64,
-) -> ProgramResult {
-    if let Some((_, balance)) = ico_state
-        .balance
-        .iter_mut()
-        .find(|(account, _)| *account == *recipient_accounts)
-    {
-        *balance += amount;
-        return Ok(());
+/// Allocation and proof passed with a presale purchase in Merkle mode.
+#[derive(Debug)]
+pub struct MerkleWhitelistProof {
+    pub allocation: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ICOError {
+    MathOverflow,
+    Paused,
+    UnsupportedVersion,
+    MaxBuyPerTxExceeded,
+    Cancelled,
+    NotCancelled,
+    AlreadyRefunded,
+    InvalidTimestamp,
+    SupplyDecrease,
+    AddressBlocked,
+    AlreadyRegistered,
+    InsufficientPhaseSupply,
+    PresaleActive,
+    SaleNotActive,
+    InvalidSaleEnd,
+    BelowRentExemption,
+    ParticipantHasPurchased,
+    NotWhitelisted,
+    InvalidVestingSchedule,
+    NothingToClaim,
+    KycRequired,
+    ContributionCapExceeded,
+    AmountNotAllowed,
+    TimelockNotElapsed,
+    NoPendingAction,
+    BatchTooLarge,
+    SoldOut,
+    InvalidTreasurySplit,
+    ZeroSupply,
+    InsufficientPresaleDiscount,
+    CoolingOffExpired,
+    ClockUnavailable,
+    BalanceNotRegistered,
+    SoftCapNotMissed,
+    SoftCapMissed,
+    CommitWindowOpen,
+    CommitWindowClosed,
+    InvalidReveal,
+    WhitelistFull,
+    TransfersDisabled,
+    NothingToRescue,
+    TermsMismatch,
+    InvalidPriceBounds,
+    InvalidTreasury,
+    AllocationBelowPurchased,
+    Reentrancy,
+    AccountTooNew,
+    SupplyBelowCirculating,
+    EntryLimitReached,
+    PhaseLimitExceeded,
+    ClaimDeadlinePassed,
+    ClaimWindowOpen,
+    AdminTokensLocked,
+    FlipLocked,
+    TermsAlreadySet,
+    MaxRoundsReached,
+}
+
+impl From<ICOError> for ProgramError {
+    fn from(e: ICOError) -> Self {
+        ProgramError::Custom(e as u32)
     }
-    ico_state.balance.push((*recipient_accounts, amount));
+}
 
-    Ok(())
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct PreSaleAccount {
+    pub address: Pubkey,
+    pub token_amount: u64,
+    pub token_price: u64,
+    pub whitelist_account: bool,
+    pub allocation: u64,
+    pub refunded: bool,
+    pub kyc_verified: bool,
+    /// Whitelist tier, `TIER_REGULAR` or `TIER_OG`.
+    pub tier: u8,
+    /// Unix time after which the whitelist spot no longer counts. Zero never
+    /// expires.
+    pub whitelist_expiry: u64,
+    /// Lamports paid for `token_amount`, summed per purchase since the price
+    /// can change between purchases.
+    pub lamports_paid: u64,
+    /// Set when `prorate_allocations` cut the allocation to zero. The entry
+    /// stays delisted, whatever proof or signature is submitted later.
+    pub prorated_out: bool,
 }
 
-pub fn whitelist_account(
-    ico_state: &mut ICOAccount,
-    account_to_whitelist: &Pubkey,
-) -> ProgramResult {
-    for pre_sale_account in &mut ico_state.pre_sale_account {
-        if &pre_sale_account.address == account_to_whitelist {
-            pre_sale_account.whitelist();
-            return Ok(());
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct SaleAccount {
+    pub address: Pubkey,
+    pub token_amount: u64,
+    pub token_price: u64,
+    pub refunded: bool,
+    pub lamports_paid: u64,
+}
+
+impl From<ICOAccountV1> for ICOAccountV2 {
+    fn from(old: ICOAccountV1) -> Self {
+        ICOAccountV2 {
+            version: 2,
+            total_supply: old.total_supply,
+            admin: old.admin,
+            balance: old.balance,
+            pre_sale_price: old.pre_sale_price,
+            pre_sale_limit: old.pre_sale_limit,
+            sale_price: old.sale_price,
+            sale_limit: old.sale_limit,
+            sale_start_time: old.sale_start_time,
+            sale_end_time: old.sale_end_time,
+            total_price_earned: old.total_price_earned,
+            pre_sale_account: old
+                .pre_sale_account
+                .into_iter()
+                .map(|pre_sale_account| PreSaleAccountV2 {
+                    address: pre_sale_account.address,
+                    token_amount: pre_sale_account.token_amount,
+                    token_price: pre_sale_account.token_price,
+                    whitelist_account: pre_sale_account.whitelist_account,
+                    allocation: 0,
+                })
+                .collect(),
+            sale_account: old.sale_account,
+            rounding_mode: RoundingMode::default(),
+            paused: false,
         }
     }
-    Err(ProgramError::InvalidAccountData)
 }
 
-pub fn pre_sale(ico_state: &mut ICOAccount, accounts: &[AccountInfo]) -> ProgramResult {
-    let acount_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
-    let buyer_account = next_account_info(acount_iter)?;
-    let current_time = Clock::get()?.unix_timestamp as u64;
+impl From<ICOAccountV2> for ICOAccount {
+    fn from(old: ICOAccountV2) -> Self {
+        // Older versions sold everything from the admin balance. What is left
+        // of it goes into the presale pool, which rolls into the sale pool at
+        // the first sale like any unsold presale supply.
+        let unsold = old
+            .balance
+            .iter()
+            .filter(|(holder, _)| *holder == old.admin)
+            .fold(0u64, |total, (_, amount)| total.saturating_add(*amount));
+        ICOAccount {
+            version: CURRENT_VERSION,
+            total_supply: old.total_supply,
+            admin: old.admin,
+            balance: old.balance,
+            pre_sale_price: old.pre_sale_price,
+            pre_sale_limit: old.pre_sale_limit,
+            sale_price: old.sale_price,
+            sale_limit: old.sale_limit,
+            sale_start_time: old.sale_start_time,
+            sale_end_time: old.sale_end_time,
+            total_price_earned: old.total_price_earned,
+            pre_sale_account: old
+                .pre_sale_account
+                .into_iter()
+                .map(|pre_sale_account| PreSaleAccount {
+                    address: pre_sale_account.address,
+                    token_amount: pre_sale_account.token_amount,
+                    token_price: pre_sale_account.token_price,
+                    whitelist_account: pre_sale_account.whitelist_account,
+                    allocation: pre_sale_account.allocation,
+                    refunded: false,
+                    kyc_verified: false,
+                    tier: TIER_REGULAR,
+                    whitelist_expiry: 0,
+                    lamports_paid: pre_sale_account
+                        .token_amount
+                        .saturating_mul(pre_sale_account.token_price),
+                    prorated_out: false,
+                })
+                .collect(),
+            sale_account: old
+                .sale_account
+                .into_iter()
+                .map(|sale_account| SaleAccount {
+                    address: sale_account.address,
+                    token_amount: sale_account.token_amount,
+                    token_price: sale_account.token_price,
+                    refunded: false,
+                    lamports_paid: sale_account
+                        .token_amount
+                        .saturating_mul(sale_account.token_price),
+                })
+                .collect(),
+            rounding_mode: old.rounding_mode,
+            paused: old.paused,
+            price_denominator: 1,
+            pre_sale_supply: unsold,
+            ..ICOAccount::default()
+        }
+    }
+}
 
-    if current_time > ico_state.sale_start_time {
-        return Err(ProgramError::InvalidInstructionData);
+impl ICOAccount {
+    /// Prices are lamports per `price_denominator` tokens, so the cost is
+    /// `amount * price / price_denominator` rounded according to
+    /// `rounding_mode`.
+    pub fn total_cost(&self, amount: u64, price: u64) -> Result<u64, ProgramError> {
+        mul_div(amount, price, self.price_denominator, self.rounding_mode)
     }
 
-    let mut is_whiltelisted = false;
-    let buyer_account_info = buyer_account.key;
-    for pre_sale_account in &ico_state.pre_sale_account {
-        if &pre_sale_account.address == buyer_account_info && pre_sale_account.whitelist_account {
-            is_whiltelisted = true;
-            break;
+    /// Returns the amount and cost of a purchase of `amount` at `price`. When
+    /// the buyer is short by no more than `lamport_tolerance`, the amount is
+    /// scaled down (rounding down) to what their spendable lamports cover and
+    /// the cost recomputed for it, which never exceeds those lamports. Amounts
+    /// that have to be bought exactly, an allowed bundle or a revealed
+    /// commitment (`fixed_amount`), are never scaled.
+    pub fn fit_to_funds(
+        &self,
+        buyer_account: &AccountInfo,
+        amount: u64,
+        price: u64,
+        fixed_amount: bool,
+    ) -> Result<(u64, u64), ProgramError> {
+        let total_cost = self.total_cost(amount, price)?;
+        if self.lamport_tolerance == 0 || fixed_amount || !self.allowed_amounts.is_empty() {
+            return Ok((amount, total_cost));
+        }
+
+        let available = buyer_account
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(buyer_account.data_len()));
+        if available >= total_cost || total_cost - available > self.lamport_tolerance {
+            return Ok((amount, total_cost));
         }
+
+        let reduced = mul_div(amount, available, total_cost, RoundingMode::Floor)?;
+        if reduced == 0 {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        msg!(
+            "Underfunded by {} lamports, buying {} of {} tokens",
+            total_cost - available,
+            reduced,
+            amount
+        );
+        Ok((reduced, self.total_cost(reduced, price)?))
     }
 
-    if !is_whiltelisted {
-        return Err(ProgramError::InvalidAccountData);
+    /// `balance` is kept sorted by pubkey so lookups can binary search and the
+    /// serialized layout doesn't depend on the order holders arrived in.
+    pub fn balance_index(&self, account: &Pubkey) -> Result<usize, usize> {
+        self.balance
+            .binary_search_by(|(holder, _)| holder.cmp(account))
     }
 
-    let amount_bytes = &buyer_account.data.borrow()[..8];
-    let amount = u64::from_le_bytes(amount_bytes.try_into().unwrap());
-    let total_cost = amount * ico_state.pre_sale_price;
+    pub fn balance_mut(&mut self, account: &Pubkey) -> Option<&mut u64> {
+        match self.balance_index(account) {
+            Ok(index) => Some(&mut self.balance[index].1),
+            Err(_) => None,
+        }
+    }
 
-    if total_cost != buyer_account.lamports() {
-        return Err(ProgramError::InvalidAccountData);
+    /// Returns the balance of `account`, inserting a zero entry at its sorted
+    /// position if it doesn't have one yet.
+    pub fn balance_entry(&mut self, account: &Pubkey) -> &mut u64 {
+        let index = match self.balance_index(account) {
+            Ok(index) => index,
+            Err(index) => {
+                self.balance.insert(index, (*account, 0));
+                index
+            }
+        };
+        &mut self.balance[index].1
     }
 
-    for pre_sale_account in &mut ico_state.pre_sale_account {
-        if &pre_sale_account.address == buyer_account_info {
-            pre_sale_account.token_amount += amount;
+    /// Balance entry credited by a purchase. Created on first buy unless
+    /// `require_balance_registration` is set, in which case the buyer has to
+    /// register it beforehand.
+    pub fn buyer_balance_entry(&mut self, buyer: &Pubkey) -> Result<&mut u64, ProgramError> {
+        if self.require_balance_registration && self.balance_index(buyer).is_err() {
+            msg!("Buyer has no registered balance entry");
+            return Err(ICOError::BalanceNotRegistered.into());
         }
+        Ok(self.balance_entry(buyer))
+    }
+
+    pub fn is_whitelisted(&self, address: &Pubkey) -> bool {
+        self.pre_sale_account.iter().any(|pre_sale_account| {
+            &pre_sale_account.address == address
+                && pre_sale_account.whitelist_account
+                && !pre_sale_account.prorated_out
+        })
     }
 
-    **buyer_account.try_borrow_mut_lamports()? -= total_cost;
+    /// Rejects re-whitelisting an entry `prorate_allocations` cut to zero.
+    pub fn check_not_prorated_out(&self, address: &Pubkey) -> ProgramResult {
+        let prorated_out = self.pre_sale_account.iter().any(|pre_sale_account| {
+            &pre_sale_account.address == address && pre_sale_account.prorated_out
+        });
+        if prorated_out {
+            msg!("Whitelist spot was prorated away");
+            return Err(ICOError::NotWhitelisted.into());
+        }
+        Ok(())
+    }
 
-    if let Some((_, buyer_balance)) = ico_state
-        .balance
-        .iter_mut()
-        .find(|(account, _)| *account == *buyer_account.key)
-    {
-        *buyer_balance += amount;
-    } else {
-        return Err(ProgramError::InvalidAccountData);
+    /// Tokens `address` has bought across both phases.
+    pub fn purchased_tokens(&self, address: &Pubkey) -> Result<u64, ProgramError> {
+        self.pre_sale_account
+            .iter()
+            .filter(|record| &record.address == address)
+            .map(|record| record.token_amount)
+            .chain(
+                self.sale_account
+                    .iter()
+                    .filter(|record| &record.address == address)
+                    .map(|record| record.token_amount),
+            )
+            .try_fold(0u64, |total, amount| total.checked_add(amount))
+            .ok_or_else(|| ICOError::MathOverflow.into())
     }
 
-    if let Some((_, admin_balance)) = ico_state
-        .balance
-        .iter_mut()
-        .find(|(account, _)| *account == ico_state.admin)
-    {
-        *admin_balance -= amount;
-    } else {
-        return Err(ProgramError::InvalidAccountData);
+    /// A sale that ended with less than `soft_cap` lamports raised. A zero
+    /// soft cap can't be missed.
+    pub fn soft_cap_missed(&self, current_time: u64) -> bool {
+        self.soft_cap != 0
+            && self.sale_phase(current_time) == SalePhase::Ended
+            && self.total_price_earned < self.soft_cap
     }
 
-    ico_state.total_price_earned += total_cost;
+    /// Checks a presale purchase against the buyer's commitment once the
+    /// commit window has closed. The commitment is `hash(amount, nonce)` and
+    /// is used up by the purchase it reveals.
+    pub fn reveal_commitment(
+        &mut self,
+        buyer: &Pubkey,
+        amount: u64,
+        nonce: Option<[u8; 32]>,
+        current_time: u64,
+    ) -> ProgramResult {
+        if current_time < self.commit_deadline {
+            msg!("Purchases open once the commit window closes");
+            return Err(ICOError::CommitWindowOpen.into());
+        }
 
-    Ok(())
-}
+        let nonce = nonce.ok_or(ProgramError::InvalidInstructionData)?;
+        let index = self
+            .purchase_commitments
+            .iter()
+            .position(|(committer, _)| committer == buyer)
+            .ok_or_else(|| {
+                msg!("Buyer has no purchase commitment");
+                ProgramError::from(ICOError::InvalidReveal)
+            })?;
+        if hashv(&[&amount.to_le_bytes(), &nonce]).to_bytes() != self.purchase_commitments[index].1
+        {
+            msg!("Reveal does not match the commitment");
+            return Err(ICOError::InvalidReveal.into());
+        }
 
-pub fn sale(ico_state: &mut ICOAccount, accounts: &[AccountInfo]) -> ProgramResult {
-    let acount_iter: &mut std::slice::Iter<'_, AccountInfo<'_>> = &mut accounts.iter();
-    let buyer_account = next_account_info(acount_iter)?;
-    let buyer_account_info = buyer_account.key;
-    let current_time = Clock::get()?.unix_timestamp as u64;
+        self.purchase_commitments.remove(index);
+        Ok(())
+    }
 
-    if current_time < ico_state.sale_start_time
-        || ico_state.sale_start_time >= ico_state.sale_end_time
-    {
-        return Err(ProgramError::InvalidInstructionData);
+    /// Entries can't be added or removed once the ICO is cancelled:
+    /// `refund_page` walks the purchase records by position, so any change
+    /// would shift buyers across its cursor.
+    pub fn check_presale_list_open(&self) -> ProgramResult {
+        if self.cancelled {
+            msg!("Presale list is frozen after cancellation");
+            return Err(ICOError::Cancelled.into());
+        }
+        Ok(())
     }
 
-    let amount_bytes = &buyer_account.data.borrow()[..8];
-    let amount = u64::from_le_bytes(amount_bytes.try_into().unwrap());
-    let total_cost = amount * ico_state.sale_price;
+    /// Makes room for one more presale entry when `max_presale_entries` is
+    /// set, following `eviction_policy`. Entries are kept in registration
+    /// order, so the first one without purchases is the oldest.
+    pub fn reserve_presale_entry(&mut self) -> ProgramResult {
+        self.check_presale_list_open()?;
+        if self.max_presale_entries == 0
+            || (self.pre_sale_account.len() as u64) < self.max_presale_entries
+        {
+            return Ok(());
+        }
 
-    if total_cost != buyer_account.lamports() {
-        return Err(ProgramError::InvalidAccountData);
+        let evictable = self
+            .pre_sale_account
+            .iter()
+            .position(|pre_sale_account| pre_sale_account.token_amount == 0);
+        match (self.eviction_policy, evictable) {
+            (EvictionPolicy::EvictOldest, Some(index)) => {
+                let evicted = self.pre_sale_account.remove(index);
+                msg!("Evicted presale entry {}", evicted.address);
+                Ok(())
+            }
+            _ => {
+                msg!("Presale whitelist is full");
+                Err(ICOError::WhitelistFull.into())
+            }
+        }
     }
 
-    for sale_account in &mut ico_state.sale_account {
-        if &sale_account.address == buyer_account_info {
-            sale_account.token_amount += amount;
+    pub fn preview_reserved(&self, address: &Pubkey) -> u64 {
+        self.preview_reservations
+            .iter()
+            .find(|(buyer, _)| buyer == address)
+            .map_or(0, |(_, reserved)| *reserved)
+    }
+
+    pub fn reserve_preview(&mut self, address: &Pubkey, amount: u64) -> ProgramResult {
+        if let Some((_, reserved)) = self
+            .preview_reservations
+            .iter_mut()
+            .find(|(buyer, _)| buyer == address)
+        {
+            *reserved = reserved.checked_add(amount).ok_or(ICOError::MathOverflow)?;
+        } else {
+            self.preview_reservations.push((*address, amount));
         }
+        Ok(())
     }
 
-    **buyer_account.try_borrow_mut_lamports()? -= total_cost;
+    /// Drops up to `amount` of the preview reservation of `address` once it
+    /// has been bought for real.
+    pub fn convert_preview_reservation(&mut self, address: &Pubkey, amount: u64) {
+        if let Some(index) = self
+            .preview_reservations
+            .iter()
+            .position(|(buyer, _)| buyer == address)
+        {
+            let reserved = self.preview_reservations[index].1.saturating_sub(amount);
+            if reserved == 0 {
+                self.preview_reservations.remove(index);
+            } else {
+                self.preview_reservations[index].1 = reserved;
+            }
+        }
+    }
 
-    if let Some((_, buyer_balance)) = ico_state
-        .balance
-        .iter_mut()
-        .find(|(account, _)| *account == *buyer_account.key)
-    {
-        *buyer_balance += amount;
-    } else {
-        return Err(ProgramError::InvalidAccountData);
+    pub fn record_first_seen(&mut self, address: &Pubkey, current_time: u64) {
+        if !self
+            .first_seen
+            .iter()
+            .any(|(participant, _)| participant == address)
+        {
+            self.first_seen.push((*address, current_time));
+        }
     }
 
-    if let Some((_, admin_balance)) = ico_state
-        .balance
-        .iter_mut()
-        .find(|(account, _)| *account == ico_state.admin)
-    {
-        *admin_balance -= amount;
-    } else {
-        return Err(ProgramError::InvalidAccountData);
+    /// Rejects participants first seen less than `min_account_age` ago, or
+    /// never seen at all, as a cheap guard against fresh sybil wallets.
+    pub fn check_account_age(&self, address: &Pubkey, current_time: u64) -> ProgramResult {
+        if self.min_account_age == 0 {
+            return Ok(());
+        }
+        let old_enough = self.first_seen.iter().any(|(participant, first_seen)| {
+            participant == address
+                && current_time.saturating_sub(*first_seen) >= self.min_account_age
+        });
+        if !old_enough {
+            msg!("Participant account is too new for the presale");
+            return Err(ICOError::AccountTooNew.into());
+        }
+        Ok(())
     }
 
-    ico_state.total_price_earned += total_cost;
+    /// Remembers when `address` first bought; later purchases keep the
+    /// original time so the cooling-off window can't be extended.
+    pub fn record_purchase_time(&mut self, address: &Pubkey, current_time: u64) {
+        if self.first_purchase_time(address).is_none() {
+            self.first_purchase_times.push((*address, current_time));
+        }
+    }
 
-    Ok(())
+    pub fn first_purchase_time(&self, address: &Pubkey) -> Option<u64> {
+        self.first_purchase_times
+            .iter()
+            .find(|(buyer, _)| buyer == address)
+            .map(|(_, time)| *time)
+    }
+
+    /// Remembers the latest presale purchase of `address`, which restarts its
+    /// flip lock.
+    pub fn record_presale_purchase_time(&mut self, address: &Pubkey, current_time: u64) {
+        if let Some((_, time)) = self
+            .presale_purchase_times
+            .iter_mut()
+            .find(|(buyer, _)| buyer == address)
+        {
+            *time = current_time;
+        } else {
+            self.presale_purchase_times.push((*address, current_time));
+        }
+    }
+
+    /// Rejects transfers of `amount` that would dip into `holder`'s presale
+    /// tokens within `flip_lock_seconds` of its latest presale purchase. Tokens
+    /// from other sources stay transferable.
+    pub fn check_flip_lock(
+        &self,
+        holder: &Pubkey,
+        amount: u64,
+        current_time: u64,
+    ) -> ProgramResult {
+        if self.flip_lock_seconds == 0 {
+            return Ok(());
+        }
+        let locked = self.presale_purchase_times.iter().any(|(buyer, time)| {
+            buyer == holder && current_time < time.saturating_add(self.flip_lock_seconds)
+        });
+        if !locked {
+            return Ok(());
+        }
+
+        let presale_tokens = self
+            .pre_sale_account
+            .iter()
+            .filter(|record| &record.address == holder && !record.refunded)
+            .try_fold(0u64, |total, record| total.checked_add(record.token_amount))
+            .ok_or(ICOError::MathOverflow)?;
+        let balance = match self.balance_index(holder) {
+            Ok(index) => self.balance[index].1,
+            Err(_) => 0,
+        };
+        if balance.saturating_sub(amount) < presale_tokens {
+            msg!("Presale tokens are still flip locked");
+            return Err(ICOError::FlipLocked.into());
+        }
+        Ok(())
+    }
+
+    /// Tracks early-bird bonus tokens credited to `address`, so an early
+    /// refund can take them back along with the purchased tokens.
+    pub fn record_bonus(&mut self, address: &Pubkey, bonus: u64) -> ProgramResult {
+        if let Some((_, total)) = self
+            .bonus_tokens
+            .iter_mut()
+            .find(|(buyer, _)| buyer == address)
+        {
+            *total = total.checked_add(bonus).ok_or(ICOError::MathOverflow)?;
+        } else {
+            self.bonus_tokens.push((*address, bonus));
+        }
+        Ok(())
+    }
+
+    /// Moves the purchased tokens of `address`'s unrefunded records and its
+    /// bonus tokens back to the admin pool and the phase supplies, leaving the
+    /// records with a zero `token_amount`. Tokens the buyer no longer holds,
+    /// e.g. after a transfer, are left where they are and counted against the
+    /// bonus first, then the sale tokens, so the phase supplies only grow by
+    /// what actually came back. Returns the number of missing tokens.
+    pub fn return_purchased_tokens(&mut self, address: &Pubkey) -> Result<u64, ProgramError> {
+        let mut pre_sale_tokens = 0u64;
+        for record in &mut self.pre_sale_account {
+            if &record.address == address && !record.refunded {
+                pre_sale_tokens = pre_sale_tokens
+                    .checked_add(record.token_amount)
+                    .ok_or(ICOError::MathOverflow)?;
+                record.token_amount = 0;
+            }
+        }
+        let mut sale_tokens = 0u64;
+        for record in &mut self.sale_account {
+            if &record.address == address && !record.refunded {
+                sale_tokens = sale_tokens
+                    .checked_add(record.token_amount)
+                    .ok_or(ICOError::MathOverflow)?;
+                record.token_amount = 0;
+            }
+        }
+        let bonus = match self
+            .bonus_tokens
+            .iter()
+            .position(|(buyer, _)| buyer == address)
+        {
+            Some(index) => self.bonus_tokens.remove(index).1,
+            None => 0,
+        };
+        let owed = pre_sale_tokens
+            .checked_add(sale_tokens)
+            .and_then(|tokens| tokens.checked_add(bonus))
+            .ok_or(ICOError::MathOverflow)?;
+
+        let returned = match self.balance_mut(address) {
+            Some(buyer_balance) => {
+                let returned = owed.min(*buyer_balance);
+                *buyer_balance -= returned;
+                returned
+            }
+            None => 0,
+        };
+        let missing = owed - returned;
+        if missing != 0 {
+            msg!(
+                "Buyer no longer holds {} of the {} tokens to return",
+                missing,
+                owed
+            );
+        }
+        let admin = self.admin;
+        let admin_balance = self.balance_entry(&admin);
+        *admin_balance = admin_balance
+            .checked_add(returned)
+            .ok_or(ICOError::MathOverflow)?;
+
+        let mut unreturned = missing.saturating_sub(bonus);
+        let sale_returned = sale_tokens.saturating_sub(unreturned);
+        unreturned -= sale_tokens - sale_returned;
+        let pre_sale_returned = pre_sale_tokens - unreturned;
+        self.pre_sale_supply = self
+            .pre_sale_supply
+            .checked_add(pre_sale_returned)
+            .ok_or(ICOError::MathOverflow)?;
+        self.sale_supply = self
+            .sale_supply
+            .checked_add(sale_returned)
+            .ok_or(ICOError::MathOverflow)?;
+        Ok(missing)
+    }
+
+    /// Purchases that take a participant's cumulative total above
+    /// `kyc_threshold` need the participant to be KYC verified. A zero
+    /// threshold disables the check.
+    pub fn check_kyc(&self, address: &Pubkey, amount: u64) -> ProgramResult {
+        if self.kyc_threshold == 0 {
+            return Ok(());
+        }
+
+        let total = self
+            .purchased_tokens(address)?
+            .checked_add(amount)
+            .ok_or(ICOError::MathOverflow)?;
+        let verified = self
+            .pre_sale_account
+            .iter()
+            .any(|record| &record.address == address && record.kyc_verified);
+        if total > self.kyc_threshold && !verified {
+            msg!("Purchase above the KYC threshold requires verification");
+            return Err(ICOError::KycRequired.into());
+        }
+        Ok(())
+    }
+
+    pub fn contributed_lamports(&self, address: &Pubkey) -> u64 {
+        self.contributions
+            .iter()
+            .find(|(contributor, _)| contributor == address)
+            .map_or(0, |(_, lamports)| *lamports)
+    }
+
+    /// Adds `lamports` to the running total paid by `address`, rejecting the
+    /// purchase if it would take the total above `max_contribution`. A zero cap
+    /// disables the check.
+    pub fn record_contribution(&mut self, address: &Pubkey, lamports: u64) -> ProgramResult {
+        let total = self
+            .contributed_lamports(address)
+            .checked_add(lamports)
+            .ok_or(ICOError::MathOverflow)?;
+        if self.max_contribution != 0 && total > self.max_contribution {
+            msg!("Purchase exceeds the per-buyer contribution cap");
+            return Err(ICOError::ContributionCapExceeded.into());
+        }
+
+        if let Some((_, contributed)) = self
+            .contributions
+            .iter_mut()
+            .find(|(contributor, _)| contributor == address)
+        {
+            *contributed = total;
+        } else {
+            self.contributions.push((*address, total));
+        }
+        Ok(())
+    }
+
+    /// `(address, token_amount, token_price)` of every presale and sale record.
+    pub fn purchase_records(&self) -> impl Iterator<Item = (&Pubkey, u64, u64)> {
+        self.pre_sale_account
+            .iter()
+            .map(|record| (&record.address, record.token_amount, record.token_price))
+            .chain(
+                self.sale_account
+                    .iter()
+                    .map(|record| (&record.address, record.token_amount, record.token_price)),
+            )
+    }
+
+    /// Lamports paid according to the purchase records, i.e. what
+    /// `total_price_earned` should be.
+    pub fn recorded_proceeds(&self) -> Result<u64, ProgramError> {
+        self.pre_sale_account
+            .iter()
+            .map(|record| record.lamports_paid)
+            .chain(self.sale_account.iter().map(|record| record.lamports_paid))
+            .try_fold(0u64, |total, paid| total.checked_add(paid))
+            .ok_or_else(|| ICOError::MathOverflow.into())
+    }
+
+    pub fn check_not_blocked(&self, account: &Pubkey) -> ProgramResult {
+        if self.blocklist.contains(account) {
+            msg!("Address is blocklisted");
+            return Err(ICOError::AddressBlocked.into());
+        }
+        Ok(())
+    }
+
+    /// Records `key` in the ring buffer of recently seen idempotency keys and
+    /// reports whether it was already there.
+    pub fn check_idempotency_key(&mut self, key: [u8; IDEMPOTENCY_KEY_LEN]) -> bool {
+        if self.idempotency_keys.contains(&key) {
+            return true;
+        }
+        let cursor = self.idempotency_cursor as usize % IDEMPOTENCY_KEY_SLOTS;
+        self.idempotency_keys[cursor] = key;
+        self.idempotency_cursor = ((cursor + 1) % IDEMPOTENCY_KEY_SLOTS) as u8;
+        false
+    }
+
+    /// Flag-only checks shared by both purchase phases. They need no scans, so
+    /// purchases are rejected before any vector is touched when they fail.
+    pub fn check_purchases_open(&self) -> ProgramResult {
+        if self.paused {
+            msg!("ICO is paused");
+            return Err(ICOError::Paused.into());
+        }
+
+        if self.cancelled {
+            msg!("ICO has been cancelled");
+            return Err(ICOError::Cancelled.into());
+        }
+
+        if self.sold_out {
+            msg!("ICO is sold out");
+            return Err(ICOError::SoldOut.into());
+        }
+        Ok(())
+    }
+
+    /// Checks the terms hash a purchase carries right after its discriminant
+    /// while `terms_hash` is set, and returns where the rest of the payload
+    /// starts.
+    pub fn accept_terms(&self, instruction_data: &[u8]) -> Result<usize, ProgramError> {
+        if self.terms_hash == [0; 32] {
+            return Ok(1);
+        }
+
+        if instruction_data.get(1..33) != Some(&self.terms_hash[..]) {
+            msg!("Purchase does not carry the current terms hash");
+            return Err(ICOError::TermsMismatch.into());
+        }
+        Ok(33)
+    }
+
+    /// Restores the sorted, one-entry-per-holder invariant of `balance` by
+    /// sorting and summing duplicate entries. Cheap when the vector is
+    /// already canonical.
+    pub fn normalize_balances(&mut self) -> ProgramResult {
+        if self.balance.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return Ok(());
+        }
+
+        msg!("Normalizing balance entries");
+        self.balance.sort_by_key(|(account, _)| *account);
+        let mut normalized: Vec<(Pubkey, u64)> = Vec::with_capacity(self.balance.len());
+        for (account, amount) in self.balance.drain(..) {
+            match normalized.last_mut() {
+                Some((last_account, last_amount)) if *last_account == account => {
+                    *last_amount = last_amount
+                        .checked_add(amount)
+                        .ok_or(ICOError::MathOverflow)?;
+                }
+                _ => normalized.push((account, amount)),
+            }
+        }
+        self.balance = normalized;
+        Ok(())
+    }
+
+    /// Phase of the ICO at `current_time`. The presale runs from
+    /// `pre_sale_start_time` up to `sale_start_time`, and the public sale from
+    /// `sale_start_time` up to `sale_end_time`.
+    pub fn sale_phase(&self, current_time: u64) -> SalePhase {
+        if current_time < self.pre_sale_start_time {
+            SalePhase::NotStarted
+        } else if current_time < self.sale_start_time {
+            SalePhase::PreSale
+        } else if current_time < self.sale_end_time {
+            SalePhase::Sale
+        } else {
+            SalePhase::Ended
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.version != 0
+    }
+
+    /// Bytes the ICO account should have to hold this state plus
+    /// `ACCOUNT_SPACE_HEADROOM`.
+    pub fn required_account_len(&self) -> Result<usize, ProgramError> {
+        Ok(self.try_to_vec()?.len() + ACCOUNT_SPACE_HEADROOM)
+    }
+
+    /// Checks that `address` is the PDA derived from `ICO_SEED` and the admin,
+    /// using the bump found at initialization instead of searching again.
+    pub fn check_ico_address(&self, program_id: &Pubkey, address: &Pubkey) -> ProgramResult {
+        let expected = Pubkey::create_program_address(
+            &[ICO_SEED, self.admin.as_ref(), &[self.ico_bump]],
+            program_id,
+        )
+        .map_err(|_| ProgramError::InvalidSeeds)?;
+        if &expected != address {
+            msg!("ICO account is not the canonical PDA");
+            return Err(ProgramError::InvalidSeeds);
+        }
+        Ok(())
+    }
+
+    /// Recomputes `sold_out`: set once the admin pool is empty, both phase
+    /// supplies are used up or `hard_cap` lamports have been raised, and
+    /// cleared again if supply is added back. A zero-supply state is always
+    /// sold out.
+    pub fn refresh_sold_out(&mut self) {
+        let admin_balance = match self.balance_index(&self.admin) {
+            Ok(index) => self.balance[index].1,
+            Err(_) => 0,
+        };
+        self.sold_out = self.total_supply == 0
+            || admin_balance == 0
+            || (self.pre_sale_supply == 0 && self.sale_supply == 0)
+            || self.hard_cap_reached();
+    }
+
+    pub fn hard_cap_reached(&self) -> bool {
+        self.hard_cap != 0 && self.total_price_earned >= self.hard_cap
+    }
+
+    /// Tokens held by anyone other than the admin, i.e. everything minted or
+    /// sold out of the admin pool so far.
+    /// Sum of all balances plus `burned_unsold`, which should equal
+    /// `total_supply`.
+    pub fn accounted_supply(&self) -> Result<u64, ProgramError> {
+        self.balance
+            .iter()
+            .try_fold(self.burned_unsold, |total, (_, amount)| {
+                total.checked_add(*amount)
+            })
+            .ok_or_else(|| ICOError::MathOverflow.into())
+    }
+
+    pub fn circulating_supply(&self) -> Result<u64, ProgramError> {
+        self.balance
+            .iter()
+            .filter(|(account, _)| *account != self.admin)
+            .try_fold(0u64, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or_else(|| ICOError::MathOverflow.into())
+    }
+
+    /// Lamports paid by `address` across presale and sale records that have
+    /// not been refunded yet.
+    pub fn unrefunded_contribution(&self, address: &Pubkey) -> Result<u64, ProgramError> {
+        let mut contribution = 0u64;
+        for pre_sale_account in &self.pre_sale_account {
+            if &pre_sale_account.address == address && !pre_sale_account.refunded {
+                contribution = contribution
+                    .checked_add(pre_sale_account.lamports_paid)
+                    .ok_or(ICOError::MathOverflow)?;
+            }
+        }
+        for sale_account in &self.sale_account {
+            if &sale_account.address == address && !sale_account.refunded {
+                contribution = contribution
+                    .checked_add(sale_account.lamports_paid)
+                    .ok_or(ICOError::MathOverflow)?;
+            }
+        }
+        Ok(contribution)
+    }
+
+    /// Whitelist tier of `address`, `TIER_REGULAR` without a presale entry.
+    pub fn tier_of(&self, address: &Pubkey) -> u8 {
+        self.pre_sale_account
+            .iter()
+            .find(|pre_sale_account| &pre_sale_account.address == address)
+            .map_or(TIER_REGULAR, |pre_sale_account| pre_sale_account.tier)
+    }
+
+    /// Presale price for buyers in `tier`, falling back to `pre_sale_price`
+    /// while no OG price is set.
+    pub fn pre_sale_price_for(&self, tier: u8) -> u64 {
+        if tier == TIER_OG && self.og_pre_sale_price != 0 {
+            self.og_pre_sale_price
+        } else {
+            self.pre_sale_price
+        }
+    }
+
+    /// Allocation of a presale entry: `og_allocation` for OG entries when it
+    /// is set, unless the entry's own allocation is smaller (e.g. after
+    /// `prorate_allocations`), otherwise the entry's own allocation. Zero means
+    /// no cap.
+    pub fn allocation_for(&self, pre_sale_account: &PreSaleAccount) -> u64 {
+        if pre_sale_account.tier == TIER_OG && self.og_allocation != 0 {
+            match pre_sale_account.allocation {
+                0 => self.og_allocation,
+                allocation => allocation.min(self.og_allocation),
+            }
+        } else {
+            pre_sale_account.allocation
+        }
+    }
+
+    /// Tokens a presale entry can still buy, `u64::MAX` for an entry without
+    /// an allocation.
+    pub fn allocation_left(&self, pre_sale_account: &PreSaleAccount) -> u64 {
+        match self.allocation_for(pre_sale_account) {
+            0 => u64::MAX,
+            allocation => allocation.saturating_sub(pre_sale_account.token_amount),
+        }
+    }
+
+    /// Checks every configured price against the smallest and largest
+    /// purchase the current limits allow: the smallest has to cost at least
+    /// one lamport, and the cost of the largest must fit in a u64.
+    pub fn check_price_bounds(&self) -> ProgramResult {
+        let min_amount = self.allowed_amounts.iter().copied().min().unwrap_or(1);
+        let max_amount = if self.max_buy_per_tx != 0 {
+            self.max_buy_per_tx
+        } else {
+            self.pre_sale_supply.saturating_add(self.sale_supply)
+        };
+
+        let mut prices = vec![self.pre_sale_price, self.sale_price];
+        if self.og_pre_sale_price != 0 {
+            prices.push(self.og_pre_sale_price);
+        }
+        for price in prices {
+            if self.total_cost(min_amount, price)? == 0 {
+                msg!(
+                    "A purchase of {} tokens at price {} would be free",
+                    min_amount,
+                    price
+                );
+                return Err(ICOError::InvalidPriceBounds.into());
+            }
+            if self.total_cost(max_amount, price).is_err() {
+                msg!(
+                    "A purchase of {} tokens at price {} would overflow",
+                    max_amount,
+                    price
+                );
+                return Err(ICOError::InvalidPriceBounds.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the presale price is at least `min_presale_discount_bps`
+    /// below the sale price. A zero minimum disables the check.
+    pub fn check_presale_discount(&self) -> ProgramResult {
+        if self.min_presale_discount_bps == 0 {
+            return Ok(());
+        }
+
+        let max_pre_sale_price = mul_div(
+            self.sale_price,
+            BPS_DENOMINATOR - self.min_presale_discount_bps,
+            BPS_DENOMINATOR,
+            RoundingMode::Floor,
+        )?;
+        if self.pre_sale_price > max_pre_sale_price || self.og_pre_sale_price > max_pre_sale_price {
+            msg!(
+                "Presale price must be at most {} to keep the minimum discount",
+                max_pre_sale_price
+            );
+            return Err(ICOError::InsufficientPresaleDiscount.into());
+        }
+        Ok(())
+    }
+
+    /// Bonus tokens for a presale purchase of `amount` made at `current_time`,
+    /// rounded down. Zero once `early_bird_deadline` has passed.
+    pub fn early_bird_bonus(&self, amount: u64, current_time: u64) -> Result<u64, ProgramError> {
+        if current_time > self.early_bird_deadline {
+            return Ok(0);
+        }
+        mul_div(
+            amount,
+            self.early_bird_bonus_bps,
+            BPS_DENOMINATOR,
+            RoundingMode::Floor,
+        )
+    }
+
+    pub fn check_allowed_amount(&self, amount: u64) -> ProgramResult {
+        if !self.allowed_amounts.is_empty() && !self.allowed_amounts.contains(&amount) {
+            msg!("Purchase amount is not one of the allowed bundles");
+            return Err(ICOError::AmountNotAllowed.into());
+        }
+        Ok(())
+    }
+
+    pub fn check_max_buy_per_tx(&self, amount: u64) -> ProgramResult {
+        if self.max_buy_per_tx != 0 && amount > self.max_buy_per_tx {
+            msg!("Purchase exceeds the per-transaction maximum");
+            return Err(ICOError::MaxBuyPerTxExceeded.into());
+        }
+        Ok(())
+    }
+
+    /// Rejects purchases above `limit`, the `pre_sale_limit` or `sale_limit`
+    /// of the phase they are made in. A zero limit disables the check.
+    pub fn check_phase_limit(&self, limit: u64, amount: u64) -> ProgramResult {
+        if limit != 0 && amount > limit {
+            msg!("Purchase exceeds the phase limit of {}", limit);
+            return Err(ICOError::PhaseLimitExceeded.into());
+        }
+        Ok(())
+    }
+
+    /// Rejects moving tokens out of the admin balance before
+    /// `admin_lockup_until`.
+    pub fn check_admin_lockup(&self, holder: &Pubkey, current_time: u64) -> ProgramResult {
+        if *holder == self.admin && current_time < self.admin_lockup_until {
+            msg!("Admin tokens are locked until {}", self.admin_lockup_until);
+            return Err(ICOError::AdminTokensLocked.into());
+        }
+        Ok(())
+    }
+}
+
+impl PreSaleAccount {
+    pub fn whitelist(&mut self) {
+        self.whitelist_account = true;
+    }
+
+    pub fn whitelist_expired(&self, current_time: u64) -> bool {
+        self.whitelist_expiry != 0 && current_time > self.whitelist_expiry
+    }
+}
+
+pub fn mul_div(
+    value: u64,
+    numerator: u64,
+    denominator: u64,
+    rounding_mode: RoundingMode,
+) -> Result<u64, ProgramError> {
+    if denominator == 0 {
+        return Err(ICOError::MathOverflow.into());
+    }
+
+    let product = (value as u128)
+        .checked_mul(numerator as u128)
+        .ok_or(ICOError::MathOverflow)?;
+    let mut result = product / denominator as u128;
+    if rounding_mode == RoundingMode::Ceil && product % denominator as u128 != 0 {
+        result += 1;
+    }
+
+    u64::try_from(result).map_err(|_| ICOError::MathOverflow.into())
+}
+
+pub fn unpack_u64(input: &[u8], offset: usize) -> Result<u64, ProgramError> {
+    input
+        .get(offset..offset + 8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or(ProgramError::InvalidInstructionData)
+}
+
+/// Logs the remaining compute units when built with the `profiling` feature
+/// (`cargo build-bpf --features profiling`). Purchases log once on entry and
+/// once before returning successfully; the difference between the two
+/// `Program consumption: N units remaining` lines in the transaction logs is
+/// the cost of the call for a given number of participants.
+#[inline(always)]
+pub fn log_compute_units() {
+    #[cfg(feature = "profiling")]
+    solana_program::log::sol_log_compute_units();
+}
+
+pub fn increment(counter: &mut u64) -> ProgramResult {
+    *counter = counter.checked_add(1).ok_or(ICOError::MathOverflow)?;
+    Ok(())
+}
+
+/// Emits a purchase event for log indexers (e.g. Geyser plugins) as a single
+/// line with the grammar
+///
+/// ```text
+/// event  = "ICO:v" version ":" kind ":" field *( ";" field )
+/// field  = key "=" value
+/// ```
+///
+/// `kind` is `PRESALE` or `SALE`, followed by the fields `buyer`, `payer`,
+/// `amount`, `bonus` and `cost`, always in that order. Pubkeys are base58 and
+/// numbers are decimal, so values never contain `:`, `;` or `=`. Fields are
+/// only added or reordered together with a bump of `EVENT_VERSION`.
+pub fn log_purchase_event(
+    kind: &str,
+    buyer: &Pubkey,
+    payer: &Pubkey,
+    amount: u64,
+    bonus: u64,
+    cost: u64,
+) {
+    msg!(
+        "ICO:v{}:{}:buyer={};payer={};amount={};bonus={};cost={}",
+        EVENT_VERSION,
+        kind,
+        buyer,
+        payer,
+        amount,
+        bonus,
+        cost
+    );
+}
+
+/// Single access point for the Clock sysvar, so a missing sysvar surfaces as
+/// `ClockUnavailable` instead of an opaque error. Only instructions that
+/// depend on time call it.
+pub fn clock() -> Result<Clock, ProgramError> {
+    Clock::get().map_err(|err| {
+        msg!("Clock sysvar unavailable: {:?}", err);
+        ICOError::ClockUnavailable.into()
+    })
+}
+
+pub fn current_unix_time() -> Result<u64, ProgramError> {
+    let unix_timestamp = clock()?.unix_timestamp;
+    u64::try_from(unix_timestamp).map_err(|_| {
+        msg!("Clock returned a negative unix timestamp");
+        ICOError::InvalidTimestamp.into()
+    })
+}
+
+/// Length of the fixed payload (discriminant included) of each admin
+/// instruction that accepts a trailing idempotency key.
+pub fn admin_payload_len(instruction_data: &[u8]) -> Option<usize> {
+    match *instruction_data.first()? {
+        1 if instruction_data.len() >= MINT_WITH_VESTING_LEN => Some(MINT_WITH_VESTING_LEN),
+        1 | 20 | 50 | 61 | 70 => Some(9),
+        23 | 53 | 60 => Some(33),
+        4 | 10 | 11 | 15 | 16 | 18 | 22 | 28 | 31 | 36 | 42 | 62 | 67 | 68 | 72 => Some(1),
+        7 | 17 | 30 | 39 | 65 => Some(10),
+        26 | 49 | 52 | 57 | 66 | 71 => Some(2),
+        _ => None,
+    }
+}
+
+/// Instructions that only read the state. They skip `normalize_balances` so
+/// `health_check` reports the state as stored.
+pub const VIEW_INSTRUCTIONS: &[u8] = &[
+    5, 9, 14, 19, 21, 25, 27, 32, 33, 34, 37, 38, 40, 44, 47, 51, 54, 56, 59, 63, 64, 69,
+];
+
+/// Returns the optional idempotency key appended after an admin instruction's
+/// payload. An all-zero key is treated as absent.
+pub fn idempotency_key(instruction_data: &[u8]) -> Option<[u8; IDEMPOTENCY_KEY_LEN]> {
+    let payload_len = admin_payload_len(instruction_data)?;
+    if instruction_data.len() != payload_len + IDEMPOTENCY_KEY_LEN {
+        return None;
+    }
+    let key: [u8; IDEMPOTENCY_KEY_LEN] = instruction_data[payload_len..].try_into().ok()?;
+    if key == [0; IDEMPOTENCY_KEY_LEN] {
+        return None;
+    }
+    Some(key)
+}
+
+/// Checks the buyer can pay `total_cost` and still stay rent exempt for the
+/// size of their account.
+pub fn check_buyer_funds(buyer_account: &AccountInfo, total_cost: u64) -> ProgramResult {
+    let remaining = buyer_account
+        .lamports()
+        .checked_sub(total_cost)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    if remaining < Rent::get()?.minimum_balance(buyer_account.data_len()) {
+        msg!("Purchase would leave the buyer below rent exemption");
+        return Err(ICOError::BelowRentExemption.into());
+    }
+    Ok(())
+}
+
+/// Rejects batch instructions with more than `MAX_BATCH_ITEMS` entries, which
+/// would not fit in the compute budget.
+pub fn check_batch_len(len: usize) -> ProgramResult {
+    if len > MAX_BATCH_ITEMS {
+        msg!(
+            "Batch of {} items exceeds the limit of {}",
+            len,
+            MAX_BATCH_ITEMS
+        );
+        return Err(ICOError::BatchTooLarge.into());
+    }
+    Ok(())
+}
+
+/// Renders a base-unit amount as a decimal string, e.g. 1500 with 3 decimals
+/// as "1.500".
+pub fn format_token_amount(amount: u64, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    format!("{}.{}", whole, fraction)
+}
+
+/// Reads an optional Merkle proof starting at `offset`: the allocation
+/// followed by the proof nodes.
+pub fn unpack_merkle_proof(
+    instruction_data: &[u8],
+    offset: usize,
+) -> Result<Option<MerkleWhitelistProof>, ProgramError> {
+    if instruction_data.len() <= offset {
+        return Ok(None);
+    }
+
+    let allocation = unpack_u64(instruction_data, offset)?;
+    let nodes = instruction_data[offset + 8..].chunks_exact(32);
+    if !nodes.remainder().is_empty() {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    check_batch_len(nodes.len())?;
+    let proof = nodes.map(|node| node.try_into().unwrap()).collect();
+
+    Ok(Some(MerkleWhitelistProof { allocation, proof }))
+}
+
+pub fn merkle_leaf(address: &Pubkey, allocation: u64) -> [u8; 32] {
+    hashv(&[address.as_ref(), &allocation.to_le_bytes()]).to_bytes()
+}
+
+/// Verifies a proof built with sorted pair hashing, so the proof doesn't need
+/// to carry left/right positions.
+pub fn verify_merkle_proof(leaf: [u8; 32], proof: &[[u8; 32]], root: &[u8; 32]) -> bool {
+    let mut computed = leaf;
+    for node in proof {
+        computed = if computed <= *node {
+            hashv(&[&computed, node]).to_bytes()
+        } else {
+            hashv(&[node, &computed]).to_bytes()
+        };
+    }
+    computed == *root
+}
+
+pub fn assert_admin(ico_state: &ICOAccount, admin_account: &AccountInfo) -> ProgramResult {
+    if !admin_account.is_signer || *admin_account.key != ico_state.admin {
+        msg!("Caller is not the admin");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+pub fn assert_whitelist_authority(
+    ico_state: &ICOAccount,
+    authority_account: &AccountInfo,
+) -> ProgramResult {
+    if !authority_account.is_signer
+        || (*authority_account.key != ico_state.admin
+            && *authority_account.key != ico_state.whitelister)
+    {
+        msg!("Caller is neither the admin nor the whitelister");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+/// Pausing is the one thing the guardian can do besides the admin.
+pub fn assert_pause_authority(
+    ico_state: &ICOAccount,
+    authority_account: &AccountInfo,
+) -> ProgramResult {
+    let is_guardian =
+        ico_state.guardian != Pubkey::default() && *authority_account.key == ico_state.guardian;
+    if !authority_account.is_signer || (*authority_account.key != ico_state.admin && !is_guardian) {
+        msg!("Caller is neither the admin nor the guardian");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(())
+}
+
+entrypoint!(process_instruction);
+
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    msg!("ICO Program Enter Point");
+
+    let account_iter = &mut accounts.iter();
+    let ico_accounts = next_account_info(account_iter)?;
+
+    if instruction_data.first() == Some(&0) && ico_accounts.data_is_empty() {
+        create_ico_account(
+            program_id,
+            ico_accounts,
+            &mut account_iter.clone(),
+            instruction_data,
+        )?;
+    }
+
+    if ico_accounts.owner != program_id {
+        msg!("ICO account does not have correct program id");
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if instruction_data.first() == Some(&6) {
+        return migrate_state(program_id, ico_accounts, account_iter, instruction_data);
+    }
+
+    let (&instruction, _) = instruction_data
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+
+    let mut ico_state = ICOAccount::deserialize(&mut &ico_accounts.data.borrow()[..])?;
+    if matches!(instruction, 2 | 3) {
+        ico_state.check_purchases_open()?;
+    }
+    if !VIEW_INSTRUCTIONS.contains(&instruction) {
+        ico_state.normalize_balances()?;
+    }
+
+    if ico_state.is_initialized() {
+        ico_state.check_ico_address(program_id, ico_accounts.key)?;
+    }
+
+    if let Some(key) = idempotency_key(instruction_data) {
+        if ico_state.check_idempotency_key(key) {
+            msg!("Idempotency key already processed, skipping");
+            return Ok(());
+        }
+    }
+
+    // Persist the lock before dispatching so a reentrant CPI sees it. The
+    // in-memory copy stays unlocked, so every successful path clears it with
+    // the final write, and a failed instruction rolls the lock back with
+    // everything else.
+    if ico_state.locked {
+        msg!("Reentrant call rejected");
+        return Err(ICOError::Reentrancy.into());
+    }
+    ico_state.locked = true;
+    ico_state.serialize(&mut &mut ico_accounts.data.borrow_mut()[..])?;
+    ico_state.locked = false;
+
+    match instruction {
+        0 => {
+            intialize_ico(
+                program_id,
+                &mut ico_state,
+                ico_accounts,
+                account_iter,
+                instruction_data,
+            )?;
+        }
+        1 => {
+            let admin_account = next_account_info(account_iter)?;
+            let recipient_account_info = next_account_info(account_iter)?;
+            assert_admin(&ico_state, admin_account)?;
+            let amount = unpack_u64(instruction_data, 1)?;
+            if instruction_data.len() >= MINT_WITH_VESTING_LEN {
+                let schedule = VestingSchedule {
+                    beneficiary: *recipient_account_info.key,
+                    total_amount: amount,
+                    claimed_amount: 0,
+                    start_time: unpack_u64(instruction_data, 9)?,
+                    cliff_time: unpack_u64(instruction_data, 17)?,
+                    end_time: unpack_u64(instruction_data, 25)?,
+                };
+                mint_vested_tokens(&mut ico_state, schedule)?;
+            } else {
+                mint_tokens(&mut ico_state, recipient_account_info.key, amount)?;
+            }
+            increment(&mut ico_state.total_mints)?;
+        }
+        2 => {
+            // After the terms hash (when set), commit-reveal mode adds the
+            // reveal nonce, and the Merkle proof, if any, comes last.
+            let offset = ico_state.accept_terms(instruction_data)?;
+            let (reveal_nonce, proof_offset) = if ico_state.commit_deadline != 0 {
+                let nonce = instruction_data
+                    .get(offset..offset + 32)
+                    .and_then(|nonce| nonce.try_into().ok())
+                    .ok_or(ProgramError::InvalidInstructionData)?;
+                (Some(nonce), offset + 32)
+            } else {
+                (None, offset)
+            };
+            let merkle_proof = unpack_merkle_proof(instruction_data, proof_offset)?;
+            pre_sale(
+                &mut ico_state,
+                ico_accounts,
+                account_iter,
+                merkle_proof,
+                reveal_nonce,
+            )?;
+            // Preview reservations move no funds and aren't purchases.
+            if !ico_state.preview_mode {
+                increment(&mut ico_state.total_purchases)?;
+            }
+        }
+        3 => {
+            ico_state.accept_terms(instruction_data)?;
+            sale(&mut ico_state, ico_accounts, account_iter)?;
+            increment(&mut ico_state.total_purchases)?;
+        }
+        4 => {
+            let account_to_whitelist_info = next_account_info(account_iter)?;
+            let authority_account = next_account_info(account_iter)?;
+            assert_whitelist_authority(&ico_state, authority_account)?;
+            whitelist_account(&mut ico_state, account_to_whitelist_info.key)?;
+        }
+        5 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            remaining_allocation(&ico_state, participant_account_info.key)?;
+        }
+        7 => {
+            let admin_account = next_account_info(account_iter)?;
+            let param = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let value = unpack_u64(instruction_data, 2)?;
+            update_config(&mut ico_state, admin_account, param, value)?;
+        }
+        8 => {
+            update_balance_commitment(&mut ico_state)?;
+        }
+        9 => {
+            supply_info(&ico_state)?;
+        }
+        10 => {
+            let admin_account = next_account_info(account_iter)?;
+            let whitelister_account = next_account_info(account_iter)?;
+            set_whitelister(&mut ico_state, admin_account, whitelister_account.key)?;
+        }
+        11 => {
+            let admin_account = next_account_info(account_iter)?;
+            cancel_ico(&mut ico_state, admin_account)?;
+        }
+        12 => {
+            let buyer_account = next_account_info(account_iter)?;
+            let authority_account = next_account_info(account_iter)?;
+            claim_refund(
+                &mut ico_state,
+                ico_accounts,
+                buyer_account,
+                authority_account,
+            )?;
+        }
+        14 => {
+            let count = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            top_holders(&ico_state, count as usize)?;
+        }
+        15 => {
+            let admin_account = next_account_info(account_iter)?;
+            let address_account = next_account_info(account_iter)?;
+            block_address(&mut ico_state, admin_account, address_account.key)?;
+        }
+        16 => {
+            let admin_account = next_account_info(account_iter)?;
+            let address_account = next_account_info(account_iter)?;
+            unblock_address(&mut ico_state, admin_account, address_account.key)?;
+        }
+        17 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            let authority_account = next_account_info(account_iter)?;
+            let allocation = unpack_u64(instruction_data, 1)?;
+            let overwrite = *instruction_data
+                .get(9)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            assert_whitelist_authority(&ico_state, authority_account)?;
+            register_whitelisted(
+                &mut ico_state,
+                participant_account_info.key,
+                allocation,
+                overwrite,
+            )?;
+            increment(&mut ico_state.total_whitelists)?;
+        }
+        18 => {
+            let admin_account = next_account_info(account_iter)?;
+            close_pre_sale(&mut ico_state, admin_account)?;
+        }
+        19 => {
+            health_check(&ico_state)?;
+        }
+        20 => {
+            let admin_account = next_account_info(account_iter)?;
+            let new_end = unpack_u64(instruction_data, 1)?;
+            extend_sale(&mut ico_state, admin_account, new_end)?;
+        }
+        21 => {
+            phase_sold_amounts(&ico_state)?;
+        }
+        22 => {
+            let admin_account = next_account_info(account_iter)?;
+            let participant_account_info = next_account_info(account_iter)?;
+            remove_presale_account(&mut ico_state, admin_account, participant_account_info.key)?;
+        }
+        23 => {
+            let admin_account = next_account_info(account_iter)?;
+            let root: [u8; 32] = instruction_data
+                .get(1..33)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            set_whitelist_root(&mut ico_state, admin_account, root)?;
+        }
+        24 => {
+            let beneficiary_account = next_account_info(account_iter)?;
+            claim_airdrop(&mut ico_state, beneficiary_account)?;
+        }
+        25 => {
+            let offset = unpack_u64(instruction_data, 1)?;
+            let len = unpack_u64(instruction_data, 9)?;
+            export_snapshot(&ico_state, offset as usize, len as usize)?;
+        }
+        26 => {
+            let admin_account = next_account_info(account_iter)?;
+            let participant_account_info = next_account_info(account_iter)?;
+            let verified = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            set_kyc_verified(
+                &mut ico_state,
+                admin_account,
+                participant_account_info.key,
+                verified,
+            )?;
+        }
+        27 => {
+            withdrawable_balance(ico_accounts)?;
+        }
+        28 => {
+            let admin_account = next_account_info(account_iter)?;
+            reconcile_total_price_earned(&mut ico_state, admin_account)?;
+        }
+        29 => {
+            let admin_account = next_account_info(account_iter)?;
+            let amounts = instruction_data[1..].chunks_exact(8);
+            if !amounts.remainder().is_empty() {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            check_batch_len(amounts.len())?;
+            let amounts = amounts
+                .map(|amount| u64::from_le_bytes(amount.try_into().unwrap()))
+                .collect();
+            set_allowed_amounts(&mut ico_state, admin_account, amounts)?;
+        }
+        30 => {
+            let admin_account = next_account_info(account_iter)?;
+            let value = unpack_u64(instruction_data, 2)?;
+            let action = match instruction_data.get(1) {
+                Some(0) => TimelockAction::SetPreSalePrice(value),
+                Some(1) => TimelockAction::SetSalePrice(value),
+                Some(2) => TimelockAction::IncreaseSupply(value),
+                Some(3) => TimelockAction::SetPriceDenominator(value),
+                Some(4) => TimelockAction::SetOgPreSalePrice(value),
+                _ => return Err(ProgramError::InvalidInstructionData),
+            };
+            queue_action(&mut ico_state, admin_account, action)?;
+        }
+        31 => {
+            let admin_account = next_account_info(account_iter)?;
+            execute_action(&mut ico_state, admin_account)?;
+        }
+        32 => {
+            current_phase(&ico_state)?;
+        }
+        33 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            total_paid(&ico_state, participant_account_info.key)?;
+        }
+        34 => {
+            let amount = unpack_u64(instruction_data, 1)?;
+            let decimals = *instruction_data
+                .get(9)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            let formatted = format_token_amount(amount, decimals);
+            msg!("{}", formatted);
+            set_return_data(formatted.as_bytes());
+        }
+        35 => {
+            let admin_account = next_account_info(account_iter)?;
+            let entries = instruction_data[1..].chunks_exact(34);
+            if !entries.remainder().is_empty() {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            check_batch_len(entries.len())?;
+            let split = entries
+                .map(|entry| {
+                    let recipient = Pubkey::new(&entry[..32]);
+                    let bps = u16::from_le_bytes(entry[32..].try_into().unwrap());
+                    (recipient, bps)
+                })
+                .collect();
+            set_treasury_split(&mut ico_state, admin_account, split)?;
+        }
+        36 => {
+            let admin_account = next_account_info(account_iter)?;
+            withdraw_proceeds(&mut ico_state, ico_accounts, admin_account, account_iter)?;
+        }
+        37 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            vesting_status(&ico_state, participant_account_info.key)?;
+        }
+        38 => {
+            let amount = unpack_u64(instruction_data, 1)?;
+            let buyer = account_iter.next().map(|buyer_account| buyer_account.key);
+            quote_cost(&ico_state, buyer, amount)?;
+        }
+        39 => {
+            let admin_account = next_account_info(account_iter)?;
+            let start_index = unpack_u64(instruction_data, 1)?;
+            let count = *instruction_data
+                .get(9)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            refund_page(
+                &mut ico_state,
+                ico_accounts,
+                admin_account,
+                account_iter,
+                start_index,
+                count as usize,
+            )?;
+        }
+        40 => {
+            let required_len = ico_state.required_account_len()? as u64;
+            set_return_data(&required_len.to_le_bytes());
+        }
+        41 => {
+            let buyer_account = next_account_info(account_iter)?;
+            early_refund(&mut ico_state, ico_accounts, buyer_account)?;
+        }
+        42 => {
+            let admin_account = next_account_info(account_iter)?;
+            snapshot_balances(&mut ico_state, admin_account)?;
+        }
+        43 => {
+            let holder_account = next_account_info(account_iter)?;
+            register_balance(&mut ico_state, holder_account)?;
+        }
+        44 => {
+            usage_counters(&ico_state)?;
+        }
+        45 => {
+            let buyer_account = next_account_info(account_iter)?;
+            claim_soft_cap_refund(&mut ico_state, ico_accounts, buyer_account)?;
+        }
+        46 => {
+            let buyer_account = next_account_info(account_iter)?;
+            let commitment = instruction_data
+                .get(1..33)
+                .and_then(|commitment| commitment.try_into().ok())
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            commit_purchase(&mut ico_state, buyer_account, commitment)?;
+        }
+        47 => {
+            let params = InitParams::try_from_slice(&instruction_data[1..])
+                .map_err(|_| ProgramError::InvalidInstructionData)?;
+            let code = match params.validate() {
+                Ok(()) => 0,
+                Err(check) => check as u8,
+            };
+            set_return_data(&[code]);
+        }
+        48 => {
+            let sender_account = next_account_info(account_iter)?;
+            let recipient_account = next_account_info(account_iter)?;
+            let amount = unpack_u64(instruction_data, 1)?;
+            transfer_tokens(
+                &mut ico_state,
+                sender_account,
+                recipient_account.key,
+                amount,
+            )?;
+        }
+        49 => {
+            let admin_account = next_account_info(account_iter)?;
+            let enabled = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            set_transfers_enabled(&mut ico_state, admin_account, enabled)?;
+        }
+        50 => {
+            let admin_account = next_account_info(account_iter)?;
+            let sender_account = next_account_info(account_iter)?;
+            let amount = unpack_u64(instruction_data, 1)?;
+            rescue_admin_transfer(&mut ico_state, admin_account, sender_account.key, amount)?;
+        }
+        51 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            holdings_breakdown(&ico_state, participant_account_info.key)?;
+        }
+        52 => {
+            let participant_account_info = next_account_info(account_iter)?;
+            let authority_account = next_account_info(account_iter)?;
+            let tier = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            assert_whitelist_authority(&ico_state, authority_account)?;
+            set_presale_tier(&mut ico_state, participant_account_info.key, tier)?;
+        }
+        53 => {
+            let admin_account = next_account_info(account_iter)?;
+            let terms_hash = instruction_data
+                .get(1..33)
+                .and_then(|terms_hash| terms_hash.try_into().ok())
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            set_terms_hash(&mut ico_state, admin_account, terms_hash)?;
+        }
+        54 => {
+            set_return_data(&ico_state.terms_hash);
+        }
+        55 => {
+            let authority_account = next_account_info(account_iter)?;
+            let addresses = instruction_data[1..].chunks_exact(32);
+            if !addresses.remainder().is_empty() {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            check_batch_len(addresses.len())?;
+            let addresses: Vec<Pubkey> = addresses.map(Pubkey::new).collect();
+            assert_whitelist_authority(&ico_state, authority_account)?;
+            batch_whitelist(&mut ico_state, &addresses)?;
+        }
+        56 => {
+            funds_accounting(&ico_state, ico_accounts)?;
+        }
+        57 => {
+            let admin_account = next_account_info(account_iter)?;
+            let treasury_account = next_account_info(account_iter)?;
+            let two_step = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            set_treasury(
+                &mut ico_state,
+                admin_account,
+                treasury_account.key,
+                two_step,
+            )?;
+        }
+        58 => {
+            let treasury_account = next_account_info(account_iter)?;
+            accept_treasury(&mut ico_state, treasury_account)?;
+        }
+        59 => {
+            export_config(&ico_state)?;
+        }
+        60 => {
+            let admin_account = next_account_info(account_iter)?;
+            let authority = instruction_data
+                .get(1..33)
+                .map(Pubkey::new)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            set_whitelist_authority(&mut ico_state, admin_account, authority)?;
+        }
+        61 => {
+            let admin_account = next_account_info(account_iter)?;
+            let participant_account_info = next_account_info(account_iter)?;
+            let new_allocation = instruction_data
+                .get(1..9)
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u64::from_le_bytes)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            set_allocation(
+                &mut ico_state,
+                admin_account,
+                participant_account_info.key,
+                new_allocation,
+            )?;
+        }
+        62 => {
+            let admin_account = next_account_info(account_iter)?;
+            let beneficiary_account = next_account_info(account_iter)?;
+            let recipient_account = next_account_info(account_iter)?;
+            force_complete_vesting(
+                &mut ico_state,
+                admin_account,
+                beneficiary_account.key,
+                recipient_account.key,
+            )?;
+        }
+        63 => {
+            let start_index = unpack_u64(instruction_data, 1)?;
+            let count = *instruction_data
+                .get(9)
+                .ok_or(ProgramError::InvalidInstructionData)?;
+            whitelist_page(&ico_state, start_index, count as usize)?;
+        }
+        64 => {
+            let budget = unpack_u64(instruction_data, 1)?;
+            let buyer = account_iter.next().map(|buyer_account| buyer_account.key);
+            quote_budget(&ico_state, buyer, budget)?;
+        }
+        65 => {
+            let admin_account = next_account_info(account_iter)?;
+            let amount = unpack_u64(instruction_data, 1)?;
+            let shrink_supply = *instruction_data
+                .get(9)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            burn_unsold(&mut ico_state, admin_account, amount, shrink_supply)?;
+        }
+        66 => {
+            let authority_account = next_account_info(account_iter)?;
+            let paused = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            set_paused(&mut ico_state, authority_account, paused)?;
+        }
+        67 => {
+            let admin_account = next_account_info(account_iter)?;
+            let guardian_account = next_account_info(account_iter)?;
+            set_guardian(&mut ico_state, admin_account, guardian_account.key)?;
+        }
+        68 => {
+            let admin_account = next_account_info(account_iter)?;
+            prorate_allocations(&mut ico_state, admin_account)?;
+        }
+        69 => {
+            active_limit(&ico_state)?;
+        }
+        70 => {
+            let admin_account = next_account_info(account_iter)?;
+            let participant_account_info = next_account_info(account_iter)?;
+            let expiry = unpack_u64(instruction_data, 1)?;
+            set_whitelist_expiry(
+                &mut ico_state,
+                admin_account,
+                participant_account_info.key,
+                expiry,
+            )?;
+        }
+        71 => {
+            let admin_account = next_account_info(account_iter)?;
+            let confirm = *instruction_data
+                .get(1)
+                .ok_or(ProgramError::InvalidInstructionData)?
+                != 0;
+            reconcile_balances(&mut ico_state, admin_account, confirm)?;
+        }
+        72 => {
+            let admin_account = next_account_info(account_iter)?;
+            sweep_unclaimed_airdrops(&mut ico_state, admin_account)?;
+        }
+        unknown => {
+            msg!("Unknown instruction discriminant: {}", unknown);
+            return Err(ProgramError::InvalidInstructionData);
+        }
+    }
+
+    ico_state.refresh_sold_out();
+    ico_state.serialize(&mut &mut ico_accounts.data.borrow_mut()[..])?;
+
+    Ok(())
+}
+
+/// Splits the init payload into the `InitParams` and the optional account size
+/// that follows them.
+pub fn unpack_init_params(
+    instruction_data: &[u8],
+) -> Result<(InitParams, Option<u64>), ProgramError> {
+    let mut rest = instruction_data
+        .get(1..)
+        .ok_or(ProgramError::InvalidInstructionData)?;
+    let params =
+        InitParams::deserialize(&mut rest).map_err(|_| ProgramError::InvalidInstructionData)?;
+    let space = match rest.len() {
+        0 => None,
+        8 => Some(u64::from_le_bytes(rest.try_into().unwrap())),
+        _ => return Err(ProgramError::InvalidInstructionData),
+    };
+    Ok((params, space))
+}
+
+/// Creates the ICO state account at the `[ICO_SEED, admin]` PDA, funded by the
+/// admin, so the state can only ever live at the canonical address. The size
+/// defaults to a fresh state plus `ACCOUNT_SPACE_HEADROOM`; there is no realloc,
+/// so launches expecting many participants pass a larger one.
+pub fn create_ico_account<'a>(
+    program_id: &Pubkey,
+    ico_accounts: &AccountInfo<'a>,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo<'a>>,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let admin_account = next_account_info(account_iter)?;
+    let system_program = next_account_info(account_iter)?;
+
+    if !admin_account.is_signer {
+        msg!("Admin must sign the initialization");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (ico_address, ico_bump) =
+        Pubkey::find_program_address(&[ICO_SEED, admin_account.key.as_ref()], program_id);
+    if ico_accounts.key != &ico_address {
+        msg!("ICO account is not the canonical PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let space = match unpack_init_params(instruction_data)?.1 {
+        Some(space) => space as usize,
+        None => ICOAccount::default().required_account_len()?,
+    };
+    let lamports = Rent::get()?.minimum_balance(space);
+    invoke_signed(
+        &system_instruction::create_account(
+            admin_account.key,
+            ico_accounts.key,
+            lamports,
+            space as u64,
+            program_id,
+        ),
+        &[
+            admin_account.clone(),
+            ico_accounts.clone(),
+            system_program.clone(),
+        ],
+        &[&[ICO_SEED, admin_account.key.as_ref(), &[ico_bump]]],
+    )
+}
+
+/// Initializes the ICO from the Borsh-encoded `InitParams` that follow the
+/// discriminant, optionally followed by the account size used when
+/// `create_ico_account` creates the state account.
+pub fn intialize_ico(
+    program_id: &Pubkey,
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let admin_account = next_account_info(account_iter)?;
+
+    if !admin_account.is_signer {
+        msg!("Admin must sign the initialization");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if ico_state.is_initialized() {
+        msg!("ICO is already initialized");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let (ico_address, ico_bump) =
+        Pubkey::find_program_address(&[ICO_SEED, admin_account.key.as_ref()], program_id);
+    if ico_accounts.key != &ico_address {
+        msg!("ICO account is not the canonical PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+    ico_state.ico_bump = ico_bump;
+
+    let (params, _) = unpack_init_params(instruction_data)?;
+    if let Err(check) = params.validate() {
+        msg!("Invalid initialization parameters: {:?}", check);
+        return Err(match check {
+            InitCheck::ZeroSupply => ICOError::ZeroSupply.into(),
+            _ => ProgramError::InvalidInstructionData,
+        });
+    }
+
+    ico_state.version = CURRENT_VERSION;
+    ico_state.admin = *admin_account.key;
+    ico_state.total_supply = params.total_supply;
+    ico_state.pre_sale_price = params.pre_sale_price;
+    ico_state.pre_sale_limit = params.pre_sale_limit;
+    ico_state.sale_price = params.sale_price;
+    ico_state.sale_limit = params.sale_limit;
+    ico_state.sale_start_time = params.sale_start_time;
+    ico_state.sale_end_time = params.sale_end_time;
+    ico_state.rounding_mode = RoundingMode::Ceil;
+    ico_state.pre_sale_supply = params.pre_sale_supply;
+    ico_state.sale_supply = params.sale_supply;
+    ico_state.price_denominator = 1;
+    ico_state.whitelist_requirement = WhitelistRequirement::PreSaleOnly;
+    *ico_state.balance_entry(admin_account.key) += ico_state.total_supply;
+    Ok(())
+}
+
+pub fn update_config(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    param: u8,
+    value: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    match param {
+        0 => ico_state.max_buy_per_tx = value,
+        1 => {
+            ico_state.rounding_mode = match value {
+                0 => RoundingMode::Floor,
+                1 => RoundingMode::Ceil,
+                _ => return Err(ProgramError::InvalidInstructionData),
+            }
+        }
+        2 => ico_state.pre_sale_supply = value,
+        3 => ico_state.sale_supply = value,
+        4 | 20 => {
+            msg!("Price changes have to be queued through the timelock");
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        5 => {
+            ico_state.whitelist_requirement = match value {
+                0 => WhitelistRequirement::None,
+                1 => WhitelistRequirement::PreSaleOnly,
+                2 => WhitelistRequirement::SaleOnly,
+                3 => WhitelistRequirement::Both,
+                _ => return Err(ProgramError::InvalidInstructionData),
+            }
+        }
+        6 => {
+            if value > BPS_DENOMINATOR {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.refund_fee_bps = value;
+        }
+        7 => {
+            if value > BPS_DENOMINATOR {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.early_bird_bonus_bps = value;
+        }
+        8 => ico_state.early_bird_deadline = value,
+        9 => ico_state.kyc_threshold = value,
+        10 => ico_state.max_contribution = value,
+        11 => ico_state.pre_sale_start_time = value,
+        12 => {
+            if value > BPS_DENOMINATOR {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.min_presale_discount_bps = value;
+            ico_state.check_presale_discount()?;
+        }
+        13 => ico_state.cooling_off_seconds = value,
+        14 => {
+            if value > BPS_DENOMINATOR {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.early_refund_penalty_bps = value;
+        }
+        15 => ico_state.require_balance_registration = value != 0,
+        16 => ico_state.soft_cap = value,
+        17 => ico_state.commit_deadline = value,
+        18 => ico_state.max_presale_entries = value,
+        19 => {
+            ico_state.eviction_policy = match value {
+                0 => EvictionPolicy::Reject,
+                1 => EvictionPolicy::EvictOldest,
+                _ => return Err(ProgramError::InvalidInstructionData),
+            }
+        }
+        21 => ico_state.og_allocation = value,
+        22 => ico_state.preview_mode = value != 0,
+        23 => ico_state.hard_cap = value,
+        24 => ico_state.lamport_tolerance = value,
+        25 => ico_state.min_account_age = value,
+        26 => {
+            if value > BPS_DENOMINATOR {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.transfer_tax_bps = value;
+        }
+        27 => ico_state.claim_deadline = value,
+        28 => {
+            if value < ico_state.admin_lockup_until {
+                msg!("Admin lockup can only be extended");
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.admin_lockup_until = value;
+        }
+        29 => ico_state.flip_lock_seconds = value,
+        _ => return Err(ProgramError::InvalidInstructionData),
+    }
+
+    if matches!(param, 0..=3) {
+        ico_state.check_price_bounds()?;
+    }
+    Ok(())
+}
+
+/// Hashes every `(pubkey, balance)` pair in pubkey order, which is the order
+/// `balance` is stored in. Only computed when requested since hashing the whole
+/// vector on every write would eat into the compute budget.
+pub fn compute_balance_commitment(ico_state: &ICOAccount) -> [u8; 32] {
+    let mut hasher = Hasher::default();
+    for (account, amount) in &ico_state.balance {
+        hasher.hash(account.as_ref());
+        hasher.hash(&amount.to_le_bytes());
+    }
+    hasher.result().to_bytes()
+}
+
+pub fn update_balance_commitment(ico_state: &mut ICOAccount) -> ProgramResult {
+    ico_state.balance_commitment = compute_balance_commitment(ico_state);
+    Ok(())
+}
+
+/// Records the balance commitment tagged with the current slot, e.g. as the
+/// reference point for a holdings-based airdrop.
+pub fn snapshot_balances(ico_state: &mut ICOAccount, admin_account: &AccountInfo) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let slot = clock()?.slot;
+    let commitment = compute_balance_commitment(ico_state);
+    if ico_state.balance_snapshots.len() >= MAX_BALANCE_SNAPSHOTS {
+        ico_state.balance_snapshots.remove(0);
+    }
+    ico_state.balance_snapshots.push((slot, commitment));
+    msg!("Balance snapshot taken at slot {}", slot);
+    Ok(())
+}
+
+pub fn register_balance(ico_state: &mut ICOAccount, holder_account: &AccountInfo) -> ProgramResult {
+    if !holder_account.is_signer {
+        msg!("Holder must sign to register a balance entry");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    if ico_state.balance_index(holder_account.key).is_err()
+        && ico_state
+            .balance
+            .iter()
+            .filter(|(_, amount)| *amount == 0)
+            .count()
+            >= MAX_REGISTERED_BALANCES
+    {
+        msg!("Balance registrations are full");
+        return Err(ICOError::EntryLimitReached.into());
+    }
+    ico_state.balance_entry(holder_account.key);
+    ico_state.record_first_seen(holder_account.key, current_unix_time()?);
+    Ok(())
+}
+
+pub fn cancel_ico(ico_state: &mut ICOAccount, admin_account: &AccountInfo) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.cancelled = true;
+    Ok(())
+}
+
+pub fn claim_refund(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    buyer_account: &AccountInfo,
+    authority_account: &AccountInfo,
+) -> ProgramResult {
+    if !ico_state.cancelled {
+        msg!("ICO has not been cancelled");
+        return Err(ICOError::NotCancelled.into());
+    }
+
+    if !authority_account.is_signer
+        || (authority_account.key != buyer_account.key && *authority_account.key != ico_state.admin)
+    {
+        msg!("Refund must be signed by the buyer or the admin");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let contribution = ico_state.unrefunded_contribution(buyer_account.key)?;
+    if contribution == 0 {
+        msg!("Nothing left to refund");
+        return Err(ICOError::AlreadyRefunded.into());
+    }
+
+    refund_buyer(ico_state, ico_accounts, buyer_account, contribution)
+}
+
+/// Refunds `contribution` to `buyer_account`, less the refund fee, takes back
+/// whatever it still holds of the tokens it bought and marks all of its
+/// purchase records as refunded.
+pub fn refund_buyer(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    buyer_account: &AccountInfo,
+    contribution: u64,
+) -> ProgramResult {
+    // The fee rounds down, so the buyer is never charged more than
+    // `refund_fee_bps`. It stays in the ICO account with the other proceeds.
+    let fee = mul_div(
+        contribution,
+        ico_state.refund_fee_bps,
+        BPS_DENOMINATOR,
+        RoundingMode::Floor,
+    )?;
+    let refund = contribution - fee;
+    ico_state.refund_fees_collected = ico_state
+        .refund_fees_collected
+        .checked_add(fee)
+        .ok_or(ICOError::MathOverflow)?;
+
+    ico_state.return_purchased_tokens(buyer_account.key)?;
+    for pre_sale_account in &mut ico_state.pre_sale_account {
+        if &pre_sale_account.address == buyer_account.key {
+            pre_sale_account.refunded = true;
+        }
+    }
+    for sale_account in &mut ico_state.sale_account {
+        if &sale_account.address == buyer_account.key {
+            sale_account.refunded = true;
+        }
+    }
+
+    let ico_lamports = ico_accounts
+        .lamports()
+        .checked_sub(refund)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let buyer_lamports = buyer_account
+        .lamports()
+        .checked_add(refund)
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_accounts.try_borrow_mut_lamports()? = ico_lamports;
+    **buyer_account.try_borrow_mut_lamports()? = buyer_lamports;
+
+    Ok(())
+}
+
+/// Lets a buyer undo their purchases while the ICO is still running, within
+/// `cooling_off_seconds` of their first purchase. The purchased and bonus
+/// tokens go back to the admin pool and phase supplies, and the refund is
+/// reduced by `early_refund_penalty_bps`, which stays in the ICO account.
+pub fn early_refund(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    buyer_account: &AccountInfo,
+) -> ProgramResult {
+    if !buyer_account.is_signer {
+        msg!("Early refund must be signed by the buyer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if ico_state.cancelled {
+        msg!("ICO has been cancelled");
+        return Err(ICOError::Cancelled.into());
+    }
+
+    let current_time = current_unix_time()?;
+    match ico_state.sale_phase(current_time) {
+        SalePhase::PreSale | SalePhase::Sale => {}
+        _ => {
+            msg!("Early refunds are only possible while the ICO is running");
+            return Err(ICOError::SaleNotActive.into());
+        }
+    }
+
+    let window_end = ico_state
+        .first_purchase_time(buyer_account.key)
+        .and_then(|time| time.checked_add(ico_state.cooling_off_seconds));
+    let in_window = matches!(window_end, Some(end) if current_time <= end);
+    if ico_state.cooling_off_seconds == 0 || !in_window {
+        msg!("Outside the cooling-off window");
+        return Err(ICOError::CoolingOffExpired.into());
+    }
+
+    let contribution = ico_state.unrefunded_contribution(buyer_account.key)?;
+    if contribution == 0 {
+        msg!("Nothing left to refund");
+        return Err(ICOError::AlreadyRefunded.into());
+    }
+
+    let penalty = mul_div(
+        contribution,
+        ico_state.early_refund_penalty_bps,
+        BPS_DENOMINATOR,
+        RoundingMode::Floor,
+    )?;
+    let refund = contribution - penalty;
+
+    for record in &mut ico_state.pre_sale_account {
+        if &record.address == buyer_account.key && !record.refunded {
+            record.lamports_paid = 0;
+        }
+    }
+    for record in &mut ico_state.sale_account {
+        if &record.address == buyer_account.key && !record.refunded {
+            record.lamports_paid = 0;
+        }
+    }
+    if ico_state.return_purchased_tokens(buyer_account.key)? != 0 {
+        msg!("Early refunds need every purchased and bonus token back");
+        return Err(ProgramError::InsufficientFunds);
+    }
+    ico_state.total_price_earned = ico_state.total_price_earned.saturating_sub(contribution);
+    ico_state.early_refund_penalties_collected = ico_state
+        .early_refund_penalties_collected
+        .checked_add(penalty)
+        .ok_or(ICOError::MathOverflow)?;
+    if let Some((_, contributed)) = ico_state
+        .contributions
+        .iter_mut()
+        .find(|(contributor, _)| contributor == buyer_account.key)
+    {
+        *contributed = contributed.saturating_sub(contribution);
+    }
+    ico_state
+        .first_purchase_times
+        .retain(|(buyer, _)| buyer != buyer_account.key);
+
+    let ico_lamports = ico_accounts
+        .lamports()
+        .checked_sub(refund)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let buyer_lamports = buyer_account
+        .lamports()
+        .checked_add(refund)
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_accounts.try_borrow_mut_lamports()? = ico_lamports;
+    **buyer_account.try_borrow_mut_lamports()? = buyer_lamports;
+
+    Ok(())
+}
+
+/// Returns exactly the lamports a buyer paid on their unrefunded purchase
+/// records once the soft cap has been missed. If the ICO account holds less
+/// than the refunds still owed, every refund is scaled down by the same ratio
+/// of available to owed lamports. The amount paid out is recorded in
+/// `soft_cap_refunds`.
+pub fn claim_soft_cap_refund(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    buyer_account: &AccountInfo,
+) -> ProgramResult {
+    if !buyer_account.is_signer {
+        msg!("Refund must be signed by the buyer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !ico_state.soft_cap_missed(current_unix_time()?) {
+        msg!("Soft cap has not been missed");
+        return Err(ICOError::SoftCapNotMissed.into());
+    }
+
+    // Records refunded through any path are skipped, so a buyer already
+    // refunded after a cancellation can't be paid a second time here.
+    let contribution = ico_state.unrefunded_contribution(buyer_account.key)?;
+    if contribution == 0 {
+        msg!("Nothing left to refund");
+        return Err(ICOError::AlreadyRefunded.into());
+    }
+
+    let outstanding = ico_state
+        .pre_sale_account
+        .iter()
+        .filter(|record| !record.refunded)
+        .map(|record| record.lamports_paid)
+        .chain(
+            ico_state
+                .sale_account
+                .iter()
+                .filter(|record| !record.refunded)
+                .map(|record| record.lamports_paid),
+        )
+        .try_fold(0u64, |total, paid| total.checked_add(paid))
+        .ok_or(ICOError::MathOverflow)?;
+
+    let available = withdrawable_lamports(ico_accounts)?;
+    let refund = if available >= outstanding {
+        contribution
+    } else {
+        msg!(
+            "Prorating refund: {} lamports available for {} owed",
+            available,
+            outstanding
+        );
+        mul_div(contribution, available, outstanding, RoundingMode::Floor)?
+    };
+
+    ico_state.return_purchased_tokens(buyer_account.key)?;
+    for pre_sale_account in &mut ico_state.pre_sale_account {
+        if &pre_sale_account.address == buyer_account.key {
+            pre_sale_account.refunded = true;
+        }
+    }
+    for sale_account in &mut ico_state.sale_account {
+        if &sale_account.address == buyer_account.key {
+            sale_account.refunded = true;
+        }
+    }
+    ico_state
+        .soft_cap_refunds
+        .push((*buyer_account.key, refund));
+
+    let ico_lamports = ico_accounts
+        .lamports()
+        .checked_sub(refund)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let buyer_lamports = buyer_account
+        .lamports()
+        .checked_add(refund)
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_accounts.try_borrow_mut_lamports()? = ico_lamports;
+    **buyer_account.try_borrow_mut_lamports()? = buyer_lamports;
+
+    Ok(())
+}
+
+/// Refunds the buyers of `count` purchase records (presale records first,
+/// then sale records) starting at `start_index`, which has to match
+/// `refund_cursor` so pages can't be skipped or replayed. The buyer account of
+/// every record in the page is passed in record order; buyers that were
+/// already refunded are skipped.
+pub fn refund_page(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    admin_account: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+    start_index: u64,
+    count: usize,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if !ico_state.cancelled {
+        msg!("ICO has not been cancelled");
+        return Err(ICOError::NotCancelled.into());
+    }
+
+    if start_index != ico_state.refund_cursor {
+        msg!("Refund page must start at {}", ico_state.refund_cursor);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+    check_batch_len(count)?;
+
+    let page: Vec<Pubkey> = ico_state
+        .purchase_records()
+        .skip(start_index as usize)
+        .take(count)
+        .map(|(address, _, _)| *address)
+        .collect();
+
+    for address in &page {
+        let buyer_account = next_account_info(account_iter)?;
+        if buyer_account.key != address {
+            msg!("Buyer account does not match purchase record");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let contribution = ico_state.unrefunded_contribution(address)?;
+        if contribution != 0 {
+            refund_buyer(ico_state, ico_accounts, buyer_account, contribution)?;
+        }
+    }
+
+    ico_state.refund_cursor = start_index + page.len() as u64;
+    Ok(())
+}
+
+pub fn increase_supply(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    new_supply: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if new_supply <= ico_state.total_supply || new_supply < ico_state.circulating_supply()? {
+        msg!("Supply can only be increased");
+        return Err(ICOError::SupplyDecrease.into());
+    }
+
+    let increase = new_supply - ico_state.total_supply;
+    let admin = ico_state.admin;
+    mint_tokens(ico_state, &admin, increase)
+}
+
+pub fn block_address(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if !ico_state.blocklist.contains(address) {
+        ico_state.blocklist.push(*address);
+    }
+    Ok(())
+}
+
+pub fn unblock_address(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.blocklist.retain(|blocked| blocked != address);
+    Ok(())
+}
+
+pub fn extend_sale(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    new_end: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if ico_state.sale_phase(current_unix_time()?) != SalePhase::Sale {
+        msg!("Sale can only be extended while it is active");
+        return Err(ICOError::SaleNotActive.into());
+    }
+
+    if new_end <= ico_state.sale_end_time {
+        msg!("Sale end time can only be moved later");
+        return Err(ICOError::InvalidSaleEnd.into());
+    }
+
+    if ico_state.sale_extensions + 1 >= MAX_ROUNDS {
+        msg!("Sale already ran {} rounds", MAX_ROUNDS);
+        return Err(ICOError::MaxRoundsReached.into());
+    }
+
+    ico_state.sale_end_time = new_end;
+    ico_state.sale_extensions += 1;
+    Ok(())
+}
+
+pub fn reconcile_total_price_earned(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let recorded_proceeds = ico_state.recorded_proceeds()?;
+    msg!(
+        "Reconciling total_price_earned: {} -> {}",
+        ico_state.total_price_earned,
+        recorded_proceeds
+    );
+    ico_state.total_price_earned = recorded_proceeds;
+    Ok(())
+}
+
+/// Restricts purchases to the given bundle sizes. An empty list allows any
+/// amount.
+pub fn set_allowed_amounts(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    amounts: Vec<u64>,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.allowed_amounts = amounts;
+    ico_state.check_price_bounds()
+}
+
+pub fn set_treasury_split(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    split: Vec<(Pubkey, u16)>,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let total_bps: u64 = split.iter().map(|(_, bps)| *bps as u64).sum();
+    if total_bps != BPS_DENOMINATOR {
+        msg!(
+            "Treasury split must sum to {} bps, got {}",
+            BPS_DENOMINATOR,
+            total_bps
+        );
+        return Err(ICOError::InvalidTreasurySplit.into());
+    }
+
+    ico_state.treasury_split = split;
+    Ok(())
+}
+
+/// Sets the address proceeds are withdrawn to when no split is configured.
+/// With `two_step` the address is only proposed and takes effect once its
+/// owner signs `accept_treasury`, which catches mistyped addresses.
+pub fn set_treasury(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    new_treasury: &Pubkey,
+    two_step: bool,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if *new_treasury == Pubkey::default() {
+        msg!("Treasury can't be the default pubkey");
+        return Err(ICOError::InvalidTreasury.into());
+    }
+
+    if two_step {
+        ico_state.pending_treasury = *new_treasury;
+    } else {
+        ico_state.treasury = *new_treasury;
+        ico_state.pending_treasury = Pubkey::default();
+    }
+    Ok(())
+}
+
+pub fn accept_treasury(
+    ico_state: &mut ICOAccount,
+    treasury_account: &AccountInfo,
+) -> ProgramResult {
+    if ico_state.pending_treasury == Pubkey::default()
+        || treasury_account.key != &ico_state.pending_treasury
+    {
+        msg!("Account is not the proposed treasury");
+        return Err(ICOError::InvalidTreasury.into());
+    }
+
+    if !treasury_account.is_signer {
+        msg!("Proposed treasury must sign to accept");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    ico_state.treasury = ico_state.pending_treasury;
+    ico_state.pending_treasury = Pubkey::default();
+    Ok(())
+}
+
+/// Pays the withdrawable lamports out to the treasury split recipients, which
+/// must be passed in the configured order, or to `treasury` when no split is
+/// set. Shares round down and the last
+/// recipient receives the remainder, so nothing is left behind. Only allowed
+/// once the sale has ended and while no refunds can be owed.
+pub fn withdraw_proceeds(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    admin_account: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if ico_state.cancelled {
+        msg!("ICO has been cancelled");
+        return Err(ICOError::Cancelled.into());
+    }
+
+    let current_time = current_unix_time()?;
+    if ico_state.sale_phase(current_time) != SalePhase::Ended {
+        msg!("Proceeds can only be withdrawn after the sale has ended");
+        return Err(ICOError::SaleNotActive.into());
+    }
+
+    if ico_state.soft_cap_missed(current_time) {
+        msg!("Soft cap was missed, proceeds are reserved for refunds");
+        return Err(ICOError::SoftCapMissed.into());
+    }
+
+    // Without a split everything goes to the treasury address.
+    let split = if !ico_state.treasury_split.is_empty() {
+        ico_state.treasury_split.clone()
+    } else if ico_state.treasury != Pubkey::default() {
+        vec![(ico_state.treasury, BPS_DENOMINATOR as u16)]
+    } else {
+        msg!("No treasury split or treasury address configured");
+        return Err(ICOError::InvalidTreasurySplit.into());
+    };
+
+    let withdrawable = withdrawable_lamports(ico_accounts)?;
+    let mut remaining = withdrawable;
+    for (index, (recipient, bps)) in split.iter().enumerate() {
+        let recipient_account = next_account_info(account_iter)?;
+        if recipient_account.key != recipient {
+            msg!("Treasury recipient {} is out of order", index);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let share = if index + 1 == split.len() {
+            remaining
+        } else {
+            mul_div(
+                withdrawable,
+                *bps as u64,
+                BPS_DENOMINATOR,
+                RoundingMode::Floor,
+            )?
+        };
+        remaining -= share;
+
+        **ico_accounts.try_borrow_mut_lamports()? -= share;
+        **recipient_account.try_borrow_mut_lamports()? = recipient_account
+            .lamports()
+            .checked_add(share)
+            .ok_or(ICOError::MathOverflow)?;
+    }
+
+    ico_state.total_withdrawn = ico_state
+        .total_withdrawn
+        .checked_add(withdrawable)
+        .ok_or(ICOError::MathOverflow)?;
+    Ok(())
+}
+
+/// Queues `action` to become executable after `TIMELOCK_DELAY`, replacing any
+/// action that is already pending.
+pub fn queue_action(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    action: TimelockAction,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let eta = current_unix_time()?
+        .checked_add(TIMELOCK_DELAY)
+        .ok_or(ICOError::MathOverflow)?;
+    msg!("Queued {:?}, executable at {}", action, eta);
+    ico_state.pending_action = Some(PendingAction { action, eta });
+    Ok(())
+}
+
+pub fn execute_action(ico_state: &mut ICOAccount, admin_account: &AccountInfo) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let pending_action = ico_state
+        .pending_action
+        .clone()
+        .ok_or(ICOError::NoPendingAction)?;
+    if current_unix_time()? < pending_action.eta {
+        msg!("Timelock has not elapsed");
+        return Err(ICOError::TimelockNotElapsed.into());
+    }
+
+    match pending_action.action {
+        TimelockAction::SetPreSalePrice(price) => ico_state.pre_sale_price = price,
+        TimelockAction::SetSalePrice(price) => ico_state.sale_price = price,
+        TimelockAction::IncreaseSupply(new_supply) => {
+            increase_supply(ico_state, admin_account, new_supply)?
+        }
+        TimelockAction::SetPriceDenominator(denominator) => {
+            if denominator == 0 {
+                return Err(ProgramError::InvalidInstructionData);
+            }
+            ico_state.price_denominator = denominator;
+        }
+        TimelockAction::SetOgPreSalePrice(price) => ico_state.og_pre_sale_price = price,
+    }
+    ico_state.check_presale_discount()?;
+    ico_state.check_price_bounds()?;
+    ico_state.pending_action = None;
+    Ok(())
+}
+
+/// Converts a version 1 or 2 account to the current layout. Those versions
+/// kept the state at an arbitrary address, while every later instruction
+/// expects the `[ICO_SEED, admin]` PDA, so the migrated state is written to the
+/// PDA and the old account is closed into it, proceeds included. The PDA is
+/// created and funded by the payer, a separate signer because the admin of a
+/// v1 account is the program id, which can't pay for it. Accounts: old state,
+/// admin, payer, PDA, system program.
+pub fn migrate_state<'a>(
+    program_id: &Pubkey,
+    ico_accounts: &AccountInfo<'a>,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo<'a>>,
+    instruction_data: &[u8],
+) -> ProgramResult {
+    let admin_account = next_account_info(account_iter)?;
+    let payer_account = next_account_info(account_iter)?;
+    let ico_pda_account = next_account_info(account_iter)?;
+    let system_program = next_account_info(account_iter)?;
+
+    if let Ok(current_state) = ICOAccount::deserialize(&mut &ico_accounts.data.borrow()[..]) {
+        if current_state.version == CURRENT_VERSION {
+            msg!("ICO account is already at the current version");
+            return Err(ICOError::UnsupportedVersion.into());
+        }
+    }
+
+    let old_state = match instruction_data.get(1) {
+        Some(1) => ICOAccountV2::from(ICOAccountV1::deserialize(
+            &mut &ico_accounts.data.borrow()[..],
+        )?),
+        Some(2) => {
+            let old_state = ICOAccountV2::deserialize(&mut &ico_accounts.data.borrow()[..])?;
+            if old_state.version != 2 {
+                msg!("ICO account is not at version 2");
+                return Err(ICOError::UnsupportedVersion.into());
+            }
+            old_state
+        }
+        _ => {
+            msg!("Only migration from versions 1 and 2 is supported");
+            return Err(ICOError::UnsupportedVersion.into());
+        }
+    };
+
+    if !admin_account.is_signer || *admin_account.key != old_state.admin {
+        msg!("Caller is not the admin");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (ico_address, ico_bump) =
+        Pubkey::find_program_address(&[ICO_SEED, old_state.admin.as_ref()], program_id);
+    if ico_pda_account.key != &ico_address || ico_accounts.key == &ico_address {
+        msg!("Migrated state must move to the canonical PDA");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let mut new_state = ICOAccount::from(old_state);
+    new_state.ico_bump = ico_bump;
+    new_state.normalize_balances()?;
+
+    if ico_pda_account.data_is_empty() {
+        let space = new_state.required_account_len()?;
+        invoke_signed(
+            &system_instruction::create_account(
+                payer_account.key,
+                ico_pda_account.key,
+                Rent::get()?.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[
+                payer_account.clone(),
+                ico_pda_account.clone(),
+                system_program.clone(),
+            ],
+            &[&[ICO_SEED, new_state.admin.as_ref(), &[ico_bump]]],
+        )?;
+    } else if ico_pda_account.owner != program_id
+        || ICOAccount::deserialize(&mut &ico_pda_account.data.borrow()[..])
+            .map_or(true, |state| state.is_initialized())
+    {
+        msg!("ICO PDA already holds a state");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let data = new_state.try_to_vec()?;
+    if data.len() > ico_pda_account.data_len() {
+        msg!(
+            "ICO PDA needs {} bytes for the migrated state, has {}",
+            data.len(),
+            ico_pda_account.data_len()
+        );
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+    ico_pda_account.data.borrow_mut()[..data.len()].copy_from_slice(&data);
+
+    let pda_lamports = ico_pda_account
+        .lamports()
+        .checked_add(ico_accounts.lamports())
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_pda_account.try_borrow_mut_lamports()? = pda_lamports;
+    **ico_accounts.try_borrow_mut_lamports()? = 0;
+    ico_accounts.data.borrow_mut().fill(0);
+
+    Ok(())
+}
+
+/// Credits newly minted tokens to the recipient. They are added to
+/// `total_supply` too, so airdrops and sweeps keep the balances summing to the
+/// supply.
+pub fn mint_tokens(
+    ico_state: &mut ICOAccount,
+    recipient_accounts: &Pubkey,
+    amount: u64,
+) -> ProgramResult {
+    ico_state.total_supply = ico_state
+        .total_supply
+        .checked_add(amount)
+        .ok_or(ICOError::MathOverflow)?;
+    let balance = ico_state.balance_entry(recipient_accounts);
+    *balance = balance.checked_add(amount).ok_or(ICOError::MathOverflow)?;
+
+    Ok(())
+}
+
+/// Moves `amount` tokens between holders. Only possible once the admin has
+/// enabled transfers; purchases are not affected by the switch.
+pub fn transfer_tokens(
+    ico_state: &mut ICOAccount,
+    sender_account: &AccountInfo,
+    recipient: &Pubkey,
+    amount: u64,
+) -> ProgramResult {
+    if !ico_state.transfers_enabled {
+        msg!("Token transfers are disabled");
+        return Err(ICOError::TransfersDisabled.into());
+    }
+
+    if !sender_account.is_signer {
+        msg!("Sender must sign the transfer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    ico_state.check_not_blocked(sender_account.key)?;
+    ico_state.check_not_blocked(recipient)?;
+    let current_time = current_unix_time()?;
+    ico_state.check_admin_lockup(sender_account.key, current_time)?;
+    ico_state.check_flip_lock(sender_account.key, amount, current_time)?;
+
+    let sender_balance = ico_state
+        .balance_mut(sender_account.key)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    *sender_balance = sender_balance
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    // The tax rounds down, so transfers too small to owe a whole token pay none.
+    let tax = mul_div(
+        amount,
+        ico_state.transfer_tax_bps,
+        BPS_DENOMINATOR,
+        RoundingMode::Floor,
+    )?;
+    let amount = amount - tax;
+    if tax != 0 {
+        let tax_recipient = if ico_state.treasury == Pubkey::default() {
+            ico_state.admin
+        } else {
+            ico_state.treasury
+        };
+        let tax_balance = ico_state.balance_entry(&tax_recipient);
+        *tax_balance = tax_balance.checked_add(tax).ok_or(ICOError::MathOverflow)?;
+    }
+
+    let recipient_balance = ico_state.balance_entry(recipient);
+    *recipient_balance = recipient_balance
+        .checked_add(amount)
+        .ok_or(ICOError::MathOverflow)?;
+
+    if *recipient == ico_state.admin {
+        if let Some((_, total)) = ico_state
+            .transfers_to_admin
+            .iter_mut()
+            .find(|(sender, _)| sender == sender_account.key)
+        {
+            *total = total.checked_add(amount).ok_or(ICOError::MathOverflow)?;
+        } else {
+            ico_state
+                .transfers_to_admin
+                .push((*sender_account.key, amount));
+        }
+    }
+    Ok(())
+}
+
+/// Sends tokens that `sender` transferred into the admin pool back to it. The
+/// amount can't exceed what is recorded in `transfers_to_admin` for that
+/// sender, so this can't be used to hand out pool tokens.
+pub fn rescue_admin_transfer(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    sender: &Pubkey,
+    amount: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let index = ico_state
+        .transfers_to_admin
+        .iter()
+        .position(|(recorded_sender, _)| recorded_sender == sender)
+        .ok_or(ICOError::NothingToRescue)?;
+    let recorded = ico_state.transfers_to_admin[index].1;
+    if amount == 0 || amount > recorded {
+        msg!("Only {} tokens from this sender can be rescued", recorded);
+        return Err(ICOError::NothingToRescue.into());
+    }
+
+    let admin = ico_state.admin;
+    let admin_balance = ico_state
+        .balance_mut(&admin)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    *admin_balance = admin_balance
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let sender_balance = ico_state.balance_entry(sender);
+    *sender_balance = sender_balance
+        .checked_add(amount)
+        .ok_or(ICOError::MathOverflow)?;
+
+    if amount == recorded {
+        ico_state.transfers_to_admin.remove(index);
+    } else {
+        ico_state.transfers_to_admin[index].1 = recorded - amount;
+    }
+    Ok(())
+}
+
+pub fn set_transfers_enabled(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    enabled: bool,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.transfers_enabled = enabled;
+    Ok(())
+}
+
+/// Records minted tokens under a vesting schedule instead of crediting them
+/// straight away. They are released to the balance through `claim_airdrop`.
+pub fn mint_vested_tokens(ico_state: &mut ICOAccount, schedule: VestingSchedule) -> ProgramResult {
+    if schedule.start_time >= schedule.end_time
+        || schedule.cliff_time < schedule.start_time
+        || schedule.cliff_time > schedule.end_time
+    {
+        msg!("Invalid vesting schedule");
+        return Err(ICOError::InvalidVestingSchedule.into());
+    }
+
+    ico_state.airdrop_vesting.push(schedule);
+    Ok(())
+}
+
+pub fn claim_airdrop(
+    ico_state: &mut ICOAccount,
+    beneficiary_account: &AccountInfo,
+) -> ProgramResult {
+    if !beneficiary_account.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let current_time = current_unix_time()?;
+    if ico_state.claim_deadline != 0 && current_time > ico_state.claim_deadline {
+        msg!("Claim deadline has passed");
+        return Err(ICOError::ClaimDeadlinePassed.into());
+    }
+
+    let mut claimed = 0u64;
+    for schedule in &mut ico_state.airdrop_vesting {
+        if &schedule.beneficiary != beneficiary_account.key {
+            continue;
+        }
+        let claimable = schedule.claimable_amount(current_time)?;
+        schedule.claimed_amount += claimable;
+        claimed = claimed
+            .checked_add(claimable)
+            .ok_or(ICOError::MathOverflow)?;
+    }
+
+    if claimed == 0 {
+        msg!("No vested airdrop tokens to claim");
+        return Err(ICOError::NothingToClaim.into());
+    }
+
+    mint_tokens(ico_state, beneficiary_account.key, claimed)
+}
+
+/// Once `claim_deadline` has passed, marks every airdrop vesting schedule
+/// fully claimed and credits the unclaimed tokens to the treasury, or to the
+/// admin while no treasury is set.
+pub fn sweep_unclaimed_airdrops(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if ico_state.claim_deadline == 0 || current_unix_time()? <= ico_state.claim_deadline {
+        msg!("Claim deadline has not passed");
+        return Err(ICOError::ClaimWindowOpen.into());
+    }
+
+    let mut unclaimed = 0u64;
+    for schedule in &mut ico_state.airdrop_vesting {
+        unclaimed = unclaimed
+            .checked_add(schedule.total_amount - schedule.claimed_amount)
+            .ok_or(ICOError::MathOverflow)?;
+        schedule.claimed_amount = schedule.total_amount;
+    }
+
+    if unclaimed == 0 {
+        msg!("No unclaimed airdrop tokens left");
+        return Err(ICOError::NothingToClaim.into());
+    }
+
+    let recipient = if ico_state.treasury == Pubkey::default() {
+        ico_state.admin
+    } else {
+        ico_state.treasury
+    };
+    msg!("Swept {} unclaimed airdrop tokens", unclaimed);
+    mint_tokens(ico_state, &recipient, unclaimed)
+}
+
+/// Marks every vesting schedule of `beneficiary` fully claimed and credits
+/// the still unclaimed tokens to `recipient`, e.g. when the beneficiary lost
+/// their key or the project winds down. Only possible once the sale ended.
+pub fn force_complete_vesting(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    beneficiary: &Pubkey,
+    recipient: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let current_time = current_unix_time()?;
+    if ico_state.sale_phase(current_time) != SalePhase::Ended {
+        msg!("Vesting can only be force completed after the sale has ended");
+        return Err(ICOError::SaleNotActive.into());
+    }
+
+    let mut remaining = 0u64;
+    for schedule in &mut ico_state.airdrop_vesting {
+        if &schedule.beneficiary != beneficiary {
+            continue;
+        }
+        remaining = remaining
+            .checked_add(schedule.total_amount - schedule.claimed_amount)
+            .ok_or(ICOError::MathOverflow)?;
+        schedule.claimed_amount = schedule.total_amount;
+    }
+
+    if remaining == 0 {
+        msg!("No unclaimed vesting tokens left");
+        return Err(ICOError::NothingToClaim.into());
+    }
+
+    msg!("Force completed vesting, crediting {} tokens", remaining);
+    mint_tokens(ico_state, recipient, remaining)
+}
+
+/// Burns `amount` unsold tokens from the admin pool once the sale has ended.
+/// With `shrink_supply` the total supply drops by the same amount, which is
+/// rejected if it would end up below the circulating supply; otherwise the
+/// burn is added to `burned_unsold`.
+pub fn burn_unsold(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    amount: u64,
+    shrink_supply: bool,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let current_time = current_unix_time()?;
+    if ico_state.sale_phase(current_time) != SalePhase::Ended {
+        msg!("Unsold tokens can only be burned after the sale has ended");
+        return Err(ICOError::SaleNotActive.into());
+    }
+    let admin = ico_state.admin;
+    ico_state.check_admin_lockup(&admin, current_time)?;
+
+    if shrink_supply {
+        let total_supply = ico_state
+            .total_supply
+            .checked_sub(amount)
+            .ok_or(ICOError::SupplyBelowCirculating)?;
+        if total_supply < ico_state.circulating_supply()? {
+            msg!("Total supply can't drop below the circulating supply");
+            return Err(ICOError::SupplyBelowCirculating.into());
+        }
+        ico_state.total_supply = total_supply;
+    }
+
+    let admin_balance = ico_state
+        .balance_mut(&admin)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    *admin_balance = admin_balance
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    if !shrink_supply {
+        ico_state.burned_unsold = ico_state
+            .burned_unsold
+            .checked_add(amount)
+            .ok_or(ICOError::MathOverflow)?;
+    }
+
+    msg!("Burned {} unsold tokens", amount);
+    Ok(())
+}
+
+pub fn set_whitelister(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    whitelister: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.whitelister = *whitelister;
+    Ok(())
+}
+
+pub fn set_guardian(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    guardian: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.guardian = *guardian;
+    Ok(())
+}
+
+pub fn set_paused(
+    ico_state: &mut ICOAccount,
+    authority_account: &AccountInfo,
+    paused: bool,
+) -> ProgramResult {
+    assert_pause_authority(ico_state, authority_account)?;
+    ico_state.paused = paused;
+    msg!("Paused: {}", paused);
+    Ok(())
+}
+
+/// Records the hash of the sale terms buyers have to agree to. It can only be
+/// set once, so the terms can't change under buyers who already agreed.
+pub fn set_terms_hash(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    terms_hash: [u8; 32],
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if ico_state.terms_hash != [0; 32] {
+        msg!("Terms hash is already set");
+        return Err(ICOError::TermsAlreadySet.into());
+    }
+    ico_state.terms_hash = terms_hash;
+    Ok(())
+}
+
+/// Sets the Merkle whitelist root and switches presale checks to Merkle mode.
+/// A zero root switches back to the on-chain list.
+pub fn set_whitelist_root(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    root: [u8; 32],
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    ico_state.whitelist_root = root;
+    ico_state.whitelist_mode = if root == [0; 32] {
+        WhitelistMode::List
+    } else {
+        WhitelistMode::Merkle
+    };
+    Ok(())
+}
+
+/// Sets the off-chain whitelist signer and switches presale checks to
+/// signature mode. A zero key switches back to the on-chain list.
+pub fn set_whitelist_authority(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    authority: Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    ico_state.whitelist_authority = authority;
+    ico_state.whitelist_mode = if authority == Pubkey::default() {
+        WhitelistMode::List
+    } else {
+        WhitelistMode::Signature
+    };
+    Ok(())
+}
+
+/// Checks a Merkle whitelist proof for `address` and records the proven
+/// allocation on its presale entry, creating the entry on first use.
+pub fn apply_merkle_whitelist(
+    ico_state: &mut ICOAccount,
+    address: &Pubkey,
+    merkle_proof: Option<MerkleWhitelistProof>,
+) -> ProgramResult {
+    ico_state.check_not_prorated_out(address)?;
+    let merkle_proof = merkle_proof.ok_or(ProgramError::InvalidInstructionData)?;
+    let leaf = merkle_leaf(address, merkle_proof.allocation);
+    if !verify_merkle_proof(leaf, &merkle_proof.proof, &ico_state.whitelist_root) {
+        msg!("Invalid whitelist proof");
+        return Err(ICOError::NotWhitelisted.into());
+    }
+    record_whitelist_allocation(ico_state, address, merkle_proof.allocation)
+}
+
+/// Checks that the instruction right before this one is an ed25519 signature
+/// check by `whitelist_authority` over `buyer || allocation (u64 LE)`, and
+/// records the signed allocation. The ed25519 program itself rejects the
+/// transaction if the signature is forged.
+pub fn apply_signature_whitelist(
+    ico_state: &mut ICOAccount,
+    address: &Pubkey,
+    instructions_sysvar: &AccountInfo,
+) -> ProgramResult {
+    if !instructions::check_id(instructions_sysvar.key) {
+        msg!("Expected the instructions sysvar");
+        return Err(ProgramError::InvalidArgument);
+    }
+    ico_state.check_not_prorated_out(address)?;
+    let current_index = instructions::load_current_index_checked(instructions_sysvar)?;
+    if current_index == 0 {
+        msg!("Missing whitelist signature instruction");
+        return Err(ICOError::NotWhitelisted.into());
+    }
+    let signature_ix =
+        instructions::load_instruction_at_checked(current_index as usize - 1, instructions_sysvar)?;
+    if !ed25519_program::check_id(&signature_ix.program_id) {
+        msg!("Previous instruction is not an ed25519 signature check");
+        return Err(ICOError::NotWhitelisted.into());
+    }
+
+    let allocation =
+        signed_whitelist_allocation(&signature_ix.data, &ico_state.whitelist_authority, address)
+            .ok_or_else(|| {
+                msg!("Whitelist signature does not cover this buyer");
+                ProgramError::from(ICOError::NotWhitelisted)
+            })?;
+    record_whitelist_allocation(ico_state, address, allocation)
+}
+
+/// Reads the allocation out of an ed25519 instruction carrying exactly one
+/// signature by `authority` over `buyer || allocation`, with the key and
+/// message stored inline in that instruction.
+pub fn signed_whitelist_allocation(data: &[u8], authority: &Pubkey, buyer: &Pubkey) -> Option<u64> {
+    if *data.first()? != 1 {
+        return None;
+    }
+    let offset = |index: usize| -> Option<u16> {
+        let start = 2 + index * 2;
+        Some(u16::from_le_bytes(
+            data.get(start..start + 2)?.try_into().ok()?,
+        ))
+    };
+    // Offsets layout: signature, signature ix, public key, public key ix,
+    // message, message size, message ix.
+    if offset(1)? != u16::MAX || offset(3)? != u16::MAX || offset(6)? != u16::MAX {
+        return None;
+    }
+    let public_key_offset = offset(2)? as usize;
+    let message_offset = offset(4)? as usize;
+    let message_size = offset(5)? as usize;
+
+    let public_key = data.get(public_key_offset..public_key_offset + 32)?;
+    if public_key != authority.as_ref() || message_size != 40 {
+        return None;
+    }
+    let message = data.get(message_offset..message_offset + message_size)?;
+    if &message[..32] != buyer.as_ref() {
+        return None;
+    }
+    Some(u64::from_le_bytes(message[32..].try_into().ok()?))
+}
+
+/// Marks `address` whitelisted with `allocation` on its presale entry,
+/// creating the entry on first use. Proofs are resubmitted with every
+/// purchase, so on an existing entry the allocation can only shrink and
+/// whatever `set_allocation` or `prorate_allocations` stored stays the cap.
+pub fn record_whitelist_allocation(
+    ico_state: &mut ICOAccount,
+    address: &Pubkey,
+    allocation: u64,
+) -> ProgramResult {
+    if let Some(pre_sale_account) = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+    {
+        pre_sale_account.whitelist_account = true;
+        // Zero means uncapped on either side.
+        pre_sale_account.allocation = match (pre_sale_account.allocation, allocation) {
+            (0, allocation) | (allocation, 0) => allocation,
+            (stored, allocation) => stored.min(allocation),
+        };
+    } else {
+        ico_state.reserve_presale_entry()?;
+        ico_state.pre_sale_account.push(PreSaleAccount {
+            address: *address,
+            token_amount: 0,
+            token_price: ico_state.pre_sale_price,
+            whitelist_account: true,
+            allocation,
+            refunded: false,
+            kyc_verified: false,
+            tier: TIER_REGULAR,
+            whitelist_expiry: 0,
+            lamports_paid: 0,
+            prorated_out: false,
+        });
+    }
+    Ok(())
+}
+
+/// Whitelists an existing presale entry. Only an actual change counts towards
+/// `total_whitelists`.
+pub fn whitelist_account(
+    ico_state: &mut ICOAccount,
+    account_to_whitelist: &Pubkey,
+) -> ProgramResult {
+    for pre_sale_account in &mut ico_state.pre_sale_account {
+        if &pre_sale_account.address == account_to_whitelist {
+            if pre_sale_account.whitelist_account {
+                msg!("Account is already whitelisted");
+                return Ok(());
+            }
+            pre_sale_account.whitelist();
+            return increment(&mut ico_state.total_whitelists);
+        }
+    }
+    Err(ProgramError::InvalidAccountData)
+}
+
+/// Whitelists every address that already has a presale entry. Unknown
+/// addresses are skipped, and the return data is a bitmap with bit `i % 8` of
+/// byte `i / 8` set when address `i` is whitelisted afterwards. Entries that
+/// already were don't count towards `total_whitelists`.
+pub fn batch_whitelist(ico_state: &mut ICOAccount, addresses: &[Pubkey]) -> ProgramResult {
+    let mut bitmap = Vec::with_capacity(addresses.len() / 8 + 1);
+    for chunk in addresses.chunks(8) {
+        let mut byte = 0u8;
+        for (bit, address) in chunk.iter().enumerate() {
+            if let Some(pre_sale_account) = ico_state
+                .pre_sale_account
+                .iter_mut()
+                .find(|pre_sale_account| &pre_sale_account.address == address)
+            {
+                if !pre_sale_account.whitelist_account {
+                    pre_sale_account.whitelist();
+                    increment(&mut ico_state.total_whitelists)?;
+                }
+                byte |= 1 << bit;
+            }
+        }
+        bitmap.push(byte);
+    }
+    set_return_data(&bitmap);
+    Ok(())
+}
+
+/// Registers `address` for the presale, whitelists it and sets its allocation
+/// in one step. An existing entry is only updated when `overwrite` is set, and
+/// keeps whatever it has already bought.
+pub fn register_whitelisted(
+    ico_state: &mut ICOAccount,
+    address: &Pubkey,
+    allocation: u64,
+    overwrite: bool,
+) -> ProgramResult {
+    if let Some(pre_sale_account) = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+    {
+        if !overwrite {
+            msg!("Address is already registered for the presale");
+            return Err(ICOError::AlreadyRegistered.into());
+        }
+        pre_sale_account.whitelist_account = true;
+        pre_sale_account.allocation = allocation;
+        return Ok(());
+    }
+
+    ico_state.reserve_presale_entry()?;
+    ico_state.pre_sale_account.push(PreSaleAccount {
+        address: *address,
+        token_amount: 0,
+        token_price: ico_state.pre_sale_price,
+        whitelist_account: true,
+        allocation,
+        refunded: false,
+        kyc_verified: false,
+        tier: TIER_REGULAR,
+        whitelist_expiry: 0,
+        lamports_paid: 0,
+        prorated_out: false,
+    });
+
+    Ok(())
+}
+
+pub fn set_presale_tier(ico_state: &mut ICOAccount, address: &Pubkey, tier: u8) -> ProgramResult {
+    if tier != TIER_REGULAR && tier != TIER_OG {
+        msg!("Unknown whitelist tier {}", tier);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let pre_sale_account = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    pre_sale_account.tier = tier;
+    Ok(())
+}
+
+/// Corrects a presale entry's allocation. It can be raised or lowered freely,
+/// but never below what the participant has already bought. Zero removes the
+/// cap.
+pub fn set_allocation(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+    new_allocation: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let pre_sale_account = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    if new_allocation != 0 && new_allocation < pre_sale_account.token_amount {
+        msg!(
+            "Allocation {} is below the {} tokens already purchased",
+            new_allocation,
+            pre_sale_account.token_amount
+        );
+        return Err(ICOError::AllocationBelowPurchased.into());
+    }
+
+    msg!(
+        "Allocation changed from {} to {}",
+        pre_sale_account.allocation,
+        new_allocation
+    );
+    pre_sale_account.allocation = new_allocation;
+    Ok(())
+}
+
+/// Scales every capped whitelisted allocation, as given by `allocation_for`,
+/// down by the same factor when together they exceed `pre_sale_supply`;
+/// uncapped entries are left alone. Each allocation is rounded down and the
+/// tokens left over go one each to the largest remainders, ties broken by
+/// address, so the result is the same on every validator and sums exactly to
+/// the supply.
+pub fn prorate_allocations(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let pool = ico_state.pre_sale_supply as u128;
+    let total: u128 = ico_state
+        .pre_sale_account
+        .iter()
+        .filter(|pre_sale_account| pre_sale_account.whitelist_account)
+        .map(|pre_sale_account| ico_state.allocation_for(pre_sale_account) as u128)
+        .sum();
+    if total <= pool {
+        msg!("Presale is not oversubscribed");
+        return Ok(());
+    }
+
+    // (index, prorated allocation, remainder)
+    let mut shares: Vec<(usize, u128, u128)> = Vec::new();
+    for (index, pre_sale_account) in ico_state.pre_sale_account.iter().enumerate() {
+        let allocation = ico_state.allocation_for(pre_sale_account);
+        if !pre_sale_account.whitelist_account || allocation == 0 {
+            continue;
+        }
+        let product = (allocation as u128)
+            .checked_mul(pool)
+            .ok_or(ICOError::MathOverflow)?;
+        shares.push((index, product / total, product % total));
+    }
+
+    let distributed: u128 = shares.iter().map(|(_, share, _)| share).sum();
+    let leftover = (pool - distributed) as usize;
+    let accounts = &ico_state.pre_sale_account;
+    shares.sort_by(|a, b| {
+        b.2.cmp(&a.2)
+            .then(accounts[a.0].address.cmp(&accounts[b.0].address))
+    });
+    for share in shares.iter_mut().take(leftover) {
+        share.1 += 1;
+    }
+
+    for (index, share, _) in shares {
+        let pre_sale_account = &mut ico_state.pre_sale_account[index];
+        let allocation = share as u64;
+        if allocation < pre_sale_account.token_amount {
+            msg!(
+                "{} already bought more than its prorated allocation",
+                pre_sale_account.address
+            );
+            return Err(ICOError::AllocationBelowPurchased.into());
+        }
+        // A zero allocation would read as uncapped, so an entry prorated down
+        // to nothing loses its whitelist spot instead.
+        if allocation == 0 {
+            pre_sale_account.whitelist_account = false;
+            pre_sale_account.prorated_out = true;
+        }
+        pre_sale_account.allocation = allocation;
+    }
+    Ok(())
+}
+
+/// Sets or extends when a participant's whitelist spot expires; zero removes
+/// the expiry.
+pub fn set_whitelist_expiry(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+    expiry: u64,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let pre_sale_account = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    pre_sale_account.whitelist_expiry = expiry;
+    Ok(())
+}
+
+pub fn remove_presale_account(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+    ico_state.check_presale_list_open()?;
+
+    let index = ico_state
+        .pre_sale_account
+        .iter()
+        .position(|pre_sale_account| &pre_sale_account.address == address)
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    if ico_state.pre_sale_account[index].token_amount != 0 {
+        msg!("Presale participant has already purchased tokens");
+        return Err(ICOError::ParticipantHasPurchased.into());
+    }
+
+    ico_state.pre_sale_account.remove(index);
+    Ok(())
+}
+
+/// Sets the KYC flag on the participant's presale entry, registering a
+/// non-whitelisted entry for participants that only take part in the sale.
+pub fn set_kyc_verified(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    address: &Pubkey,
+    verified: bool,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if let Some(pre_sale_account) = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == address)
+    {
+        pre_sale_account.kyc_verified = verified;
+        return Ok(());
+    }
+
+    ico_state.reserve_presale_entry()?;
+    ico_state.pre_sale_account.push(PreSaleAccount {
+        address: *address,
+        token_amount: 0,
+        token_price: ico_state.pre_sale_price,
+        whitelist_account: false,
+        allocation: 0,
+        refunded: false,
+        kyc_verified: verified,
+        tier: TIER_REGULAR,
+        whitelist_expiry: 0,
+        lamports_paid: 0,
+        prorated_out: false,
+    });
+    Ok(())
+}
+
+pub fn remaining_allocation(ico_state: &ICOAccount, participant: &Pubkey) -> ProgramResult {
+    let pre_sale_account = ico_state
+        .pre_sale_account
+        .iter()
+        .find(|pre_sale_account| &pre_sale_account.address == participant)
+        .ok_or(ProgramError::InvalidAccountData)?;
+
+    let remaining = ico_state.allocation_left(pre_sale_account);
+    ReturnEnvelope::set(
+        RETURN_REMAINING_ALLOCATION,
+        remaining.to_le_bytes().to_vec(),
+    )
+}
+
+/// Returns up to `count` whitelisted addresses from `pre_sale_account`,
+/// skipping the first `start_index` whitelisted ones.
+pub fn whitelist_page(ico_state: &ICOAccount, start_index: u64, count: usize) -> ProgramResult {
+    if count > MAX_WHITELIST_PAGE {
+        msg!("At most {} addresses fit in one page", MAX_WHITELIST_PAGE);
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let page: Vec<Pubkey> = ico_state
+        .pre_sale_account
+        .iter()
+        .filter(|pre_sale_account| pre_sale_account.whitelist_account)
+        .skip(start_index as usize)
+        .take(count)
+        .map(|pre_sale_account| pre_sale_account.address)
+        .collect();
+    ReturnEnvelope::set(RETURN_WHITELIST_PAGE, page.try_to_vec()?)
+}
+
+pub fn supply_info(ico_state: &ICOAccount) -> ProgramResult {
+    if !ico_state.is_initialized() {
+        msg!("ICO account is not initialized");
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    let mut data = ico_state.total_supply.to_le_bytes().to_vec();
+    data.extend_from_slice(&ico_state.circulating_supply()?.to_le_bytes());
+    ReturnEnvelope::set(RETURN_SUPPLY_INFO, data)
+}
+
+/// Returns up to `count` balances, largest first. Equal balances are ordered by
+/// pubkey so the output is the same on every validator.
+pub fn top_holders(ico_state: &ICOAccount, count: usize) -> ProgramResult {
+    if count > MAX_TOP_HOLDERS {
+        msg!("Requested too many holders");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let mut holders: Vec<(Pubkey, u64)> = ico_state.balance.clone();
+    holders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    holders.truncate(count);
+    ReturnEnvelope::set(RETURN_TOP_HOLDERS, holders.try_to_vec()?)
+}
+
+/// Moves whatever is left of the presale pool into the public sale pool. Runs
+/// automatically on the first sale after the presale window closes, and can
+/// also be triggered by the admin.
+pub fn rollover_pre_sale_supply(ico_state: &mut ICOAccount) -> ProgramResult {
+    if ico_state.pre_sale_supply == 0 {
+        return Ok(());
+    }
+
+    msg!(
+        "Rolling {} unsold presale tokens into the sale",
+        ico_state.pre_sale_supply
+    );
+    ico_state.sale_supply = ico_state
+        .sale_supply
+        .checked_add(ico_state.pre_sale_supply)
+        .ok_or(ICOError::MathOverflow)?;
+    ico_state.pre_sale_supply = 0;
+
+    Ok(())
+}
+
+pub fn close_pre_sale(ico_state: &mut ICOAccount, admin_account: &AccountInfo) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    if matches!(
+        ico_state.sale_phase(current_unix_time()?),
+        SalePhase::NotStarted | SalePhase::PreSale
+    ) {
+        msg!("Presale has not ended yet");
+        return Err(ICOError::PresaleActive.into());
+    }
+
+    rollover_pre_sale_supply(ico_state)
+}
+
+/// Compares the sum of all balances plus `burned_unsold` with `total_supply`
+/// and returns both, as found before any repair, as little-endian u64s. With
+/// `confirm`, the admin balance absorbs the difference so they match again.
+pub fn reconcile_balances(
+    ico_state: &mut ICOAccount,
+    admin_account: &AccountInfo,
+    confirm: bool,
+) -> ProgramResult {
+    assert_admin(ico_state, admin_account)?;
+
+    let total_balance = ico_state.accounted_supply()?;
+    let total_supply = ico_state.total_supply;
+
+    if confirm && total_balance != total_supply {
+        let admin = ico_state.admin;
+        let admin_balance = ico_state.balance_entry(&admin);
+        let old_balance = *admin_balance;
+        *admin_balance = if total_supply > total_balance {
+            old_balance
+                .checked_add(total_supply - total_balance)
+                .ok_or(ICOError::MathOverflow)?
+        } else {
+            old_balance
+                .checked_sub(total_balance - total_supply)
+                .ok_or(ProgramError::InsufficientFunds)?
+        };
+        msg!(
+            "Admin balance reconciled from {} to {}",
+            old_balance,
+            *admin_balance
+        );
+    }
+
+    let mut data = total_balance.to_le_bytes().to_vec();
+    data.extend_from_slice(&total_supply.to_le_bytes());
+    ReturnEnvelope::set(RETURN_BALANCE_RECONCILIATION, data)
+}
+
+/// Recomputes the state invariants and returns a `HEALTH_*` bitmask of the ones
+/// that don't hold. A zero mask means the account is consistent.
+pub fn health_check(ico_state: &ICOAccount) -> ProgramResult {
+    let mut violations = 0u8;
+
+    if ico_state.accounted_supply()? != ico_state.total_supply {
+        violations |= HEALTH_SUPPLY_MISMATCH;
+    }
+
+    let recorded_tokens = ico_state
+        .purchase_records()
+        .try_fold(0u64, |total, (_, token_amount, _)| {
+            total.checked_add(token_amount)
+        })
+        .ok_or(ICOError::MathOverflow)?;
+    if recorded_tokens > ico_state.circulating_supply()? {
+        violations |= HEALTH_RECORDS_EXCEED_BALANCES;
+    }
+    if ico_state.recorded_proceeds()? != ico_state.total_price_earned {
+        violations |= HEALTH_PROCEEDS_MISMATCH;
+    }
+
+    if ico_state
+        .balance
+        .windows(2)
+        .any(|pair| pair[0].0 >= pair[1].0)
+    {
+        violations |= HEALTH_BALANCES_UNSORTED;
+    }
+
+    set_return_data(&[violations]);
+
+    Ok(())
+}
+
+pub fn phase_sold_amounts(ico_state: &ICOAccount) -> ProgramResult {
+    let pre_sale_sold = ico_state
+        .pre_sale_account
+        .iter()
+        .try_fold(0u64, |total, record| total.checked_add(record.token_amount))
+        .ok_or(ICOError::MathOverflow)?;
+    let sale_sold = ico_state
+        .sale_account
+        .iter()
+        .try_fold(0u64, |total, record| total.checked_add(record.token_amount))
+        .ok_or(ICOError::MathOverflow)?;
+
+    let mut data = pre_sale_sold.to_le_bytes().to_vec();
+    data.extend_from_slice(&sale_sold.to_le_bytes());
+    set_return_data(&data);
+
+    Ok(())
+}
+
+/// Returns `len` bytes of the Borsh-encoded `ICOSnapshot` starting at
+/// `offset`, prefixed with the snapshot's total length as a u64 so clients
+/// know how many chunks to request.
+pub fn export_snapshot(ico_state: &ICOAccount, offset: usize, len: usize) -> ProgramResult {
+    let snapshot = ICOSnapshot {
+        snapshot_version: SNAPSHOT_VERSION,
+        account_version: ico_state.version,
+        admin: ico_state.admin,
+        total_supply: ico_state.total_supply,
+        pre_sale_price: ico_state.pre_sale_price,
+        sale_price: ico_state.sale_price,
+        price_denominator: ico_state.price_denominator,
+        sale_start_time: ico_state.sale_start_time,
+        sale_end_time: ico_state.sale_end_time,
+        total_price_earned: ico_state.total_price_earned,
+        balances: ico_state.balance.clone(),
+    };
+    let encoded = snapshot.try_to_vec()?;
+
+    if offset > encoded.len() || len > MAX_RETURN_DATA_LEN - 8 {
+        msg!("Invalid snapshot chunk");
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let end = encoded.len().min(offset.saturating_add(len));
+    let mut data = (encoded.len() as u64).to_le_bytes().to_vec();
+    data.extend_from_slice(&encoded[offset..end]);
+    set_return_data(&data);
+
+    Ok(())
+}
+
+/// Returns the Borsh-encoded `ICOConfigV2`.
+pub fn export_config(ico_state: &ICOAccount) -> ProgramResult {
+    let config = ICOConfigV2 {
+        config_version: CONFIG_VERSION,
+        account_version: ico_state.version,
+        admin: ico_state.admin,
+        whitelister: ico_state.whitelister,
+        treasury: ico_state.treasury,
+        total_supply: ico_state.total_supply,
+        pre_sale_price: ico_state.pre_sale_price,
+        og_pre_sale_price: ico_state.og_pre_sale_price,
+        pre_sale_limit: ico_state.pre_sale_limit,
+        sale_price: ico_state.sale_price,
+        sale_limit: ico_state.sale_limit,
+        price_denominator: ico_state.price_denominator,
+        rounding_mode: ico_state.rounding_mode,
+        pre_sale_start_time: ico_state.pre_sale_start_time,
+        sale_start_time: ico_state.sale_start_time,
+        sale_end_time: ico_state.sale_end_time,
+        pre_sale_supply: ico_state.pre_sale_supply,
+        sale_supply: ico_state.sale_supply,
+        max_buy_per_tx: ico_state.max_buy_per_tx,
+        max_contribution: ico_state.max_contribution,
+        kyc_threshold: ico_state.kyc_threshold,
+        whitelist_requirement: ico_state.whitelist_requirement,
+        whitelist_mode: ico_state.whitelist_mode,
+        whitelist_root: ico_state.whitelist_root,
+        max_presale_entries: ico_state.max_presale_entries,
+        eviction_policy: ico_state.eviction_policy,
+        og_allocation: ico_state.og_allocation,
+        refund_fee_bps: ico_state.refund_fee_bps,
+        early_bird_bonus_bps: ico_state.early_bird_bonus_bps,
+        early_bird_deadline: ico_state.early_bird_deadline,
+        min_presale_discount_bps: ico_state.min_presale_discount_bps,
+        cooling_off_seconds: ico_state.cooling_off_seconds,
+        early_refund_penalty_bps: ico_state.early_refund_penalty_bps,
+        soft_cap: ico_state.soft_cap,
+        hard_cap: ico_state.hard_cap,
+        commit_deadline: ico_state.commit_deadline,
+        terms_hash: ico_state.terms_hash,
+        paused: ico_state.paused,
+        cancelled: ico_state.cancelled,
+        sold_out: ico_state.sold_out,
+        transfers_enabled: ico_state.transfers_enabled,
+        preview_mode: ico_state.preview_mode,
+        require_balance_registration: ico_state.require_balance_registration,
+        whitelist_authority: ico_state.whitelist_authority,
+        lamport_tolerance: ico_state.lamport_tolerance,
+        min_account_age: ico_state.min_account_age,
+        guardian: ico_state.guardian,
+        transfer_tax_bps: ico_state.transfer_tax_bps,
+        claim_deadline: ico_state.claim_deadline,
+        admin_lockup_until: ico_state.admin_lockup_until,
+        flip_lock_seconds: ico_state.flip_lock_seconds,
+    };
+    set_return_data(&config.try_to_vec()?);
+    Ok(())
+}
+
+/// Lamports held by the ICO account above its rent-exempt minimum.
+pub fn withdrawable_lamports(ico_accounts: &AccountInfo) -> Result<u64, ProgramError> {
+    let rent_minimum = Rent::get()?.minimum_balance(ico_accounts.data_len());
+    Ok(ico_accounts.lamports().saturating_sub(rent_minimum))
+}
+
+pub fn withdrawable_balance(ico_accounts: &AccountInfo) -> ProgramResult {
+    set_return_data(&withdrawable_lamports(ico_accounts)?.to_le_bytes());
+    Ok(())
+}
+
+/// Returns `total_price_earned`, `total_withdrawn` and the currently
+/// withdrawable lamports, each as a little-endian u64.
+pub fn funds_accounting(ico_state: &ICOAccount, ico_accounts: &AccountInfo) -> ProgramResult {
+    let mut data = Vec::with_capacity(24);
+    for value in &[
+        ico_state.total_price_earned,
+        ico_state.total_withdrawn,
+        withdrawable_lamports(ico_accounts)?,
+    ] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    set_return_data(&data);
+    Ok(())
+}
+
+pub fn current_phase(ico_state: &ICOAccount) -> ProgramResult {
+    let phase = ico_state.sale_phase(current_unix_time()?);
+    set_return_data(&[phase as u8]);
+    Ok(())
+}
+
+/// Returns the purchase, mint and whitelist instruction counts, each as a
+/// little-endian u64.
+pub fn usage_counters(ico_state: &ICOAccount) -> ProgramResult {
+    let mut data = Vec::with_capacity(24);
+    for counter in &[
+        ico_state.total_purchases,
+        ico_state.total_mints,
+        ico_state.total_whitelists,
+    ] {
+        data.extend_from_slice(&counter.to_le_bytes());
+    }
+    set_return_data(&data);
+    Ok(())
+}
+
+/// Returns what buying `amount` in the current phase would cost, computed the
+/// same way a purchase is charged. Presale quotes use the tier price of
+/// `buyer` when one is given.
+pub fn quote_cost(ico_state: &ICOAccount, buyer: Option<&Pubkey>, amount: u64) -> ProgramResult {
+    let price = match ico_state.sale_phase(current_unix_time()?) {
+        SalePhase::PreSale => ico_state
+            .pre_sale_price_for(buyer.map_or(TIER_REGULAR, |buyer| ico_state.tier_of(buyer))),
+        SalePhase::Sale => ico_state.sale_price,
+        _ => {
+            msg!("No purchase phase is active");
+            return Err(ICOError::SaleNotActive.into());
+        }
+    };
+
+    let cost = ico_state.total_cost(amount, price)?;
+    set_return_data(&cost.to_le_bytes());
+    Ok(())
+}
+
+/// Returns the purchase limit of the phase that is active right now: the
+/// presale limit during the presale and the sale limit during the sale.
+pub fn active_limit(ico_state: &ICOAccount) -> ProgramResult {
+    let limit = match ico_state.sale_phase(current_unix_time()?) {
+        SalePhase::PreSale => ico_state.pre_sale_limit,
+        SalePhase::Sale => ico_state.sale_limit,
+        _ => {
+            msg!("No purchase phase is active");
+            return Err(ICOError::SaleNotActive.into());
+        }
+    };
+    ReturnEnvelope::set(RETURN_ACTIVE_LIMIT, limit.to_le_bytes().to_vec())
+}
+
+/// Returns the most whole tokens `budget` lamports buy in the current phase,
+/// and what they cost, as two little-endian u64s. The amount is capped by the
+/// remaining phase supply, the phase limit, `max_buy_per_tx`, whatever is left
+/// below the hard cap and `max_contribution`, and is rounded down to an allowed
+/// bundle size. Given a `buyer`, presale quotes use their tier price and
+/// remaining allocation, and the contribution cap counts what they already
+/// paid.
+pub fn quote_budget(ico_state: &ICOAccount, buyer: Option<&Pubkey>, budget: u64) -> ProgramResult {
+    let pre_sale_account = buyer.and_then(|buyer| {
+        ico_state
+            .pre_sale_account
+            .iter()
+            .find(|pre_sale_account| &pre_sale_account.address == buyer)
+    });
+    let (price, supply, limit, allocation_left) = match ico_state.sale_phase(current_unix_time()?) {
+        SalePhase::PreSale => (
+            ico_state.pre_sale_price_for(
+                pre_sale_account.map_or(TIER_REGULAR, |pre_sale_account| pre_sale_account.tier),
+            ),
+            ico_state.pre_sale_supply,
+            ico_state.pre_sale_limit,
+            pre_sale_account.map_or(u64::MAX, |pre_sale_account| {
+                ico_state.allocation_left(pre_sale_account)
+            }),
+        ),
+        SalePhase::Sale => (
+            ico_state.sale_price,
+            ico_state.sale_supply,
+            ico_state.sale_limit,
+            u64::MAX,
+        ),
+        _ => {
+            msg!("No purchase phase is active");
+            return Err(ICOError::SaleNotActive.into());
+        }
+    };
+
+    let mut budget = budget;
+    if ico_state.hard_cap != 0 {
+        budget = budget.min(
+            ico_state
+                .hard_cap
+                .saturating_sub(ico_state.total_price_earned),
+        );
+    }
+    if ico_state.max_contribution != 0 {
+        let contributed = buyer.map_or(0, |buyer| ico_state.contributed_lamports(buyer));
+        budget = budget.min(ico_state.max_contribution.saturating_sub(contributed));
+    }
+
+    // Rounding down keeps the cost within budget under either rounding mode.
+    let mut amount = if price == 0 {
+        supply
+    } else {
+        mul_div(
+            budget,
+            ico_state.price_denominator,
+            price,
+            RoundingMode::Floor,
+        )?
+    };
+    amount = amount.min(supply).min(allocation_left);
+    if limit != 0 {
+        amount = amount.min(limit);
+    }
+    if ico_state.max_buy_per_tx != 0 {
+        amount = amount.min(ico_state.max_buy_per_tx);
+    }
+    if !ico_state.allowed_amounts.is_empty() {
+        amount = ico_state
+            .allowed_amounts
+            .iter()
+            .copied()
+            .filter(|allowed| *allowed <= amount)
+            .max()
+            .unwrap_or(0);
+    }
+
+    let cost = ico_state.total_cost(amount, price)?;
+    let mut data = amount.to_le_bytes().to_vec();
+    data.extend_from_slice(&cost.to_le_bytes());
+    ReturnEnvelope::set(RETURN_BUDGET_QUOTE, data)
+}
+
+/// Returns the cumulative lamports `participant` has paid across presale and
+/// sale purchases, as charged at the time of each purchase.
+pub fn total_paid(ico_state: &ICOAccount, participant: &Pubkey) -> ProgramResult {
+    let paid = ico_state.contributed_lamports(participant);
+    set_return_data(&paid.to_le_bytes());
+    Ok(())
+}
+
+/// Returns `participant`'s presale tokens, sale tokens and total balance, each
+/// as a little-endian u64. The balance also includes bonus, minted and
+/// transferred tokens, so it need not equal the sum of the first two.
+pub fn holdings_breakdown(ico_state: &ICOAccount, participant: &Pubkey) -> ProgramResult {
+    let pre_sale_tokens = ico_state
+        .pre_sale_account
+        .iter()
+        .filter(|record| &record.address == participant)
+        .try_fold(0u64, |total, record| total.checked_add(record.token_amount))
+        .ok_or(ICOError::MathOverflow)?;
+    let sale_tokens = ico_state
+        .sale_account
+        .iter()
+        .filter(|record| &record.address == participant)
+        .try_fold(0u64, |total, record| total.checked_add(record.token_amount))
+        .ok_or(ICOError::MathOverflow)?;
+    let total_tokens = match ico_state.balance_index(participant) {
+        Ok(index) => ico_state.balance[index].1,
+        Err(_) => 0,
+    };
+
+    let mut data = Vec::with_capacity(24);
+    for value in &[pre_sale_tokens, sale_tokens, total_tokens] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    set_return_data(&data);
+    Ok(())
+}
+
+/// Returns total, claimed and claimable amounts plus the next unlock time
+/// (0 when there is none) summed over `participant`'s vesting schedules, each
+/// as a little-endian u64. A participant without vesting gets all zeros.
+pub fn vesting_status(ico_state: &ICOAccount, participant: &Pubkey) -> ProgramResult {
+    let current_time = current_unix_time()?;
+    let (mut total, mut claimed, mut claimable) = (0u64, 0u64, 0u64);
+    let mut next_unlock: Option<u64> = None;
+
+    for schedule in ico_state
+        .airdrop_vesting
+        .iter()
+        .filter(|schedule| &schedule.beneficiary == participant)
+    {
+        total = total
+            .checked_add(schedule.total_amount)
+            .ok_or(ICOError::MathOverflow)?;
+        claimed = claimed
+            .checked_add(schedule.claimed_amount)
+            .ok_or(ICOError::MathOverflow)?;
+        claimable = claimable
+            .checked_add(schedule.claimable_amount(current_time)?)
+            .ok_or(ICOError::MathOverflow)?;
+        if let Some(unlock) = schedule.next_unlock_time(current_time) {
+            next_unlock = Some(next_unlock.map_or(unlock, |next| next.min(unlock)));
+        }
+    }
+
+    let mut data = Vec::with_capacity(32);
+    for value in &[total, claimed, claimable, next_unlock.unwrap_or(0)] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    set_return_data(&data);
+    Ok(())
+}
+
+/// Reads the purchase amount from the first 8 bytes of the buyer account's
+/// data, failing instead of panicking when the account is too small.
+pub fn purchase_amount(buyer_account: &AccountInfo) -> Result<u64, ProgramError> {
+    let data = buyer_account.try_borrow_data()?;
+    data.get(..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_le_bytes)
+        .ok_or_else(|| {
+            msg!("Buyer account data is too small to hold the purchase amount");
+            ProgramError::AccountDataTooSmall
+        })
+}
+
+/// Address that receives the tokens of a purchase. Taken from the optional
+/// account following the payer, so custodians can buy on behalf of another
+/// wallet; defaults to the payer. Lamports always come from the payer, which
+/// has to sign when buying for someone else.
+pub fn purchase_beneficiary(
+    buyer_account: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+) -> Result<Pubkey, ProgramError> {
+    match account_iter.next() {
+        Some(beneficiary_account) if beneficiary_account.key != buyer_account.key => {
+            if !buyer_account.is_signer {
+                msg!("Payer must sign purchases made for another wallet");
+                return Err(ProgramError::MissingRequiredSignature);
+            }
+            Ok(*beneficiary_account.key)
+        }
+        _ => Ok(*buyer_account.key),
+    }
+}
+
+pub fn pre_sale(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+    merkle_proof: Option<MerkleWhitelistProof>,
+    reveal_nonce: Option<[u8; 32]>,
+) -> ProgramResult {
+    log_compute_units();
+    let buyer_account = next_account_info(account_iter)?;
+    let beneficiary = purchase_beneficiary(buyer_account, account_iter)?;
+    let current_time = current_unix_time()?;
+
+    ico_state.check_purchases_open()?;
+
+    if ico_state.sale_phase(current_time) != SalePhase::PreSale {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let buyer_account_info = &beneficiary;
+    ico_state.check_not_blocked(buyer_account.key)?;
+    ico_state.check_not_blocked(buyer_account_info)?;
+
+    if ico_state.whitelist_requirement.pre_sale() {
+        match ico_state.whitelist_mode {
+            WhitelistMode::List => {
+                if !ico_state.is_whitelisted(buyer_account_info) {
+                    return Err(ProgramError::InvalidAccountData);
+                }
+            }
+            WhitelistMode::Merkle => {
+                apply_merkle_whitelist(ico_state, buyer_account_info, merkle_proof)?;
+            }
+            WhitelistMode::Signature => {
+                // The beneficiary slot must be filled (with the buyer again for
+                // self purchases) so the sysvar isn't taken as the beneficiary.
+                let instructions_sysvar = next_account_info(account_iter)?;
+                apply_signature_whitelist(ico_state, buyer_account_info, instructions_sysvar)?;
+            }
+        }
+
+        let expired = ico_state.pre_sale_account.iter().any(|pre_sale_account| {
+            &pre_sale_account.address == buyer_account_info
+                && pre_sale_account.whitelist_expired(current_time)
+        });
+        if expired {
+            msg!("Whitelist spot has expired");
+            return Err(ICOError::NotWhitelisted.into());
+        }
+    }
+
+    let amount = purchase_amount(buyer_account)?;
+    ico_state.check_max_buy_per_tx(amount)?;
+    ico_state.check_phase_limit(ico_state.pre_sale_limit, amount)?;
+    ico_state.check_allowed_amount(amount)?;
+
+    // Preview reservations are free, so they are limited to whitelisted
+    // buyers, and they leave the commitment for the real purchase.
+    if ico_state.preview_mode && !ico_state.is_whitelisted(buyer_account_info) {
+        msg!("Preview reservations are limited to whitelisted buyers");
+        return Err(ICOError::NotWhitelisted.into());
+    }
+    // Commitments are signed and stored under the payer, also when buying
+    // for someone else.
+    if ico_state.commit_deadline != 0 && !ico_state.preview_mode {
+        ico_state.reveal_commitment(buyer_account.key, amount, reveal_nonce, current_time)?;
+    }
+
+    ico_state.check_kyc(buyer_account_info, amount)?;
+    ico_state.check_account_age(buyer_account_info, current_time)?;
+
+    if amount > ico_state.pre_sale_supply {
+        msg!("Not enough presale supply left");
+        return Err(ICOError::InsufficientPhaseSupply.into());
+    }
+
+    let pre_sale_price = ico_state.pre_sale_price_for(ico_state.tier_of(buyer_account_info));
+    // Previews move no funds, so the buyer's lamports don't matter yet.
+    let (amount, total_cost) = if ico_state.preview_mode {
+        (amount, 0)
+    } else {
+        ico_state.fit_to_funds(
+            buyer_account,
+            amount,
+            pre_sale_price,
+            ico_state.commit_deadline != 0,
+        )?
+    };
+
+    // Preview reservations count against the allocation while previewing; a
+    // real purchase afterwards converts them instead.
+    let reserved = if ico_state.preview_mode {
+        ico_state.preview_reserved(buyer_account_info)
+    } else {
+        0
+    };
+    for pre_sale_account in &ico_state.pre_sale_account {
+        let allocation = ico_state.allocation_for(pre_sale_account);
+        if &pre_sale_account.address != buyer_account_info || allocation == 0 {
+            continue;
+        }
+        let requested = pre_sale_account
+            .token_amount
+            .checked_add(reserved)
+            .and_then(|total| total.checked_add(amount))
+            .ok_or(ICOError::MathOverflow)?;
+        if requested > allocation {
+            msg!("Purchase exceeds presale allocation");
+            return Err(ProgramError::InvalidInstructionData);
+        }
+    }
+
+    if ico_state.preview_mode {
+        msg!("Preview: reserving {} tokens, no funds moved", amount);
+        return ico_state.reserve_preview(buyer_account_info, amount);
+    }
+    check_buyer_funds(buyer_account, total_cost)?;
+    ico_state.convert_preview_reservation(buyer_account_info, amount);
+
+    ico_state.record_contribution(buyer_account_info, total_cost)?;
+    ico_state.record_purchase_time(buyer_account_info, current_time);
+    ico_state.record_presale_purchase_time(buyer_account_info, current_time);
+    ico_state.record_first_seen(buyer_account_info, current_time);
+
+    if let Some(pre_sale_account) = ico_state
+        .pre_sale_account
+        .iter_mut()
+        .find(|pre_sale_account| &pre_sale_account.address == buyer_account_info)
+    {
+        pre_sale_account.token_amount = pre_sale_account
+            .token_amount
+            .checked_add(amount)
+            .ok_or(ICOError::MathOverflow)?;
+        pre_sale_account.token_price = pre_sale_price;
+        pre_sale_account.lamports_paid = pre_sale_account
+            .lamports_paid
+            .checked_add(total_cost)
+            .ok_or(ICOError::MathOverflow)?;
+    } else {
+        // Unlisted buyers are only let in when the presale needs no
+        // whitelist; their purchase still needs a record for refunds.
+        ico_state.reserve_presale_entry()?;
+        ico_state.pre_sale_account.push(PreSaleAccount {
+            address: *buyer_account_info,
+            token_amount: amount,
+            token_price: pre_sale_price,
+            whitelist_account: false,
+            allocation: 0,
+            refunded: false,
+            kyc_verified: false,
+            tier: TIER_REGULAR,
+            whitelist_expiry: 0,
+            lamports_paid: total_cost,
+            prorated_out: false,
+        });
+    }
+
+    let buyer_lamports = buyer_account
+        .lamports()
+        .checked_sub(total_cost)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **buyer_account.try_borrow_mut_lamports()? = buyer_lamports;
+    let ico_lamports = ico_accounts
+        .lamports()
+        .checked_add(total_cost)
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_accounts.try_borrow_mut_lamports()? = ico_lamports;
+
+    let bonus = ico_state.early_bird_bonus(amount, current_time)?;
+    let credited = amount.checked_add(bonus).ok_or(ICOError::MathOverflow)?;
+    let balance = ico_state.buyer_balance_entry(buyer_account_info)?;
+    *balance = balance
+        .checked_add(credited)
+        .ok_or(ICOError::MathOverflow)?;
+    if bonus != 0 {
+        ico_state.record_bonus(buyer_account_info, bonus)?;
+    }
+
+    let admin = ico_state.admin;
+    if let Some(admin_balance) = ico_state.balance_mut(&admin) {
+        *admin_balance = admin_balance
+            .checked_sub(credited)
+            .ok_or(ProgramError::InsufficientFunds)?;
+    } else {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    ico_state.pre_sale_supply = ico_state
+        .pre_sale_supply
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    ico_state.total_price_earned = ico_state
+        .total_price_earned
+        .checked_add(total_cost)
+        .ok_or(ICOError::MathOverflow)?;
+    if ico_state.hard_cap_reached() {
+        msg!("Hard cap reached, closing purchases");
+        ico_state.sold_out = true;
+    }
+
+    log_purchase_event(
+        "PRESALE",
+        buyer_account_info,
+        buyer_account.key,
+        amount,
+        bonus,
+        total_cost,
+    );
+
+    log_compute_units();
+    Ok(())
+}
+
+/// Stores `hash(amount, nonce)` for a presale purchase to be revealed after
+/// `commit_deadline` by the same payer, whoever the beneficiary is. Committing
+/// again before the deadline replaces the previous commitment.
+pub fn commit_purchase(
+    ico_state: &mut ICOAccount,
+    buyer_account: &AccountInfo,
+    commitment: [u8; 32],
+) -> ProgramResult {
+    if !buyer_account.is_signer {
+        msg!("Buyer must sign the commitment");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let current_time = current_unix_time()?;
+    if ico_state.commit_deadline == 0
+        || ico_state.sale_phase(current_time) != SalePhase::PreSale
+        || current_time >= ico_state.commit_deadline
+    {
+        msg!("Commit window is closed");
+        return Err(ICOError::CommitWindowClosed.into());
+    }
+    ico_state.check_not_blocked(buyer_account.key)?;
+
+    if let Some((_, existing)) = ico_state
+        .purchase_commitments
+        .iter_mut()
+        .find(|(committer, _)| committer == buyer_account.key)
+    {
+        *existing = commitment;
+    } else {
+        if ico_state.purchase_commitments.len() >= MAX_PURCHASE_COMMITMENTS {
+            msg!("Too many pending commitments");
+            return Err(ICOError::EntryLimitReached.into());
+        }
+        ico_state
+            .purchase_commitments
+            .push((*buyer_account.key, commitment));
+    }
+    Ok(())
+}
+
+pub fn sale(
+    ico_state: &mut ICOAccount,
+    ico_accounts: &AccountInfo,
+    account_iter: &mut std::slice::Iter<'_, AccountInfo>,
+) -> ProgramResult {
+    log_compute_units();
+    let buyer_account = next_account_info(account_iter)?;
+    let beneficiary = purchase_beneficiary(buyer_account, account_iter)?;
+    let buyer_account_info = &beneficiary;
+    let current_time = current_unix_time()?;
+
+    ico_state.check_purchases_open()?;
+
+    if ico_state.sale_phase(current_time) != SalePhase::Sale {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    ico_state.check_not_blocked(buyer_account.key)?;
+    ico_state.check_not_blocked(buyer_account_info)?;
+
+    if ico_state.whitelist_requirement.sale() && !ico_state.is_whitelisted(buyer_account_info) {
+        msg!("Sale requires a whitelisted buyer");
+        return Err(ICOError::NotWhitelisted.into());
+    }
+
+    let amount = purchase_amount(buyer_account)?;
+    ico_state.check_max_buy_per_tx(amount)?;
+    ico_state.check_phase_limit(ico_state.sale_limit, amount)?;
+    ico_state.check_allowed_amount(amount)?;
+
+    ico_state.check_kyc(buyer_account_info, amount)?;
+
+    rollover_pre_sale_supply(ico_state)?;
+
+    if amount > ico_state.sale_supply {
+        msg!("Not enough sale supply left");
+        return Err(ICOError::InsufficientPhaseSupply.into());
+    }
+
+    let (amount, total_cost) =
+        ico_state.fit_to_funds(buyer_account, amount, ico_state.sale_price, false)?;
+
+    check_buyer_funds(buyer_account, total_cost)?;
+    ico_state.record_contribution(buyer_account_info, total_cost)?;
+    ico_state.record_purchase_time(buyer_account_info, current_time);
+    ico_state.record_first_seen(buyer_account_info, current_time);
+
+    if let Some(sale_account) = ico_state
+        .sale_account
+        .iter_mut()
+        .find(|sale_account| &sale_account.address == buyer_account_info)
+    {
+        sale_account.token_amount = sale_account
+            .token_amount
+            .checked_add(amount)
+            .ok_or(ICOError::MathOverflow)?;
+        sale_account.lamports_paid = sale_account
+            .lamports_paid
+            .checked_add(total_cost)
+            .ok_or(ICOError::MathOverflow)?;
+    } else {
+        ico_state.sale_account.push(SaleAccount {
+            address: *buyer_account_info,
+            token_amount: amount,
+            token_price: ico_state.sale_price,
+            refunded: false,
+            lamports_paid: total_cost,
+        });
+    }
+
+    let buyer_lamports = buyer_account
+        .lamports()
+        .checked_sub(total_cost)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    **buyer_account.try_borrow_mut_lamports()? = buyer_lamports;
+    let ico_lamports = ico_accounts
+        .lamports()
+        .checked_add(total_cost)
+        .ok_or(ICOError::MathOverflow)?;
+    **ico_accounts.try_borrow_mut_lamports()? = ico_lamports;
+
+    let balance = ico_state.buyer_balance_entry(buyer_account_info)?;
+    *balance = balance.checked_add(amount).ok_or(ICOError::MathOverflow)?;
+
+    let admin = ico_state.admin;
+    if let Some(admin_balance) = ico_state.balance_mut(&admin) {
+        *admin_balance = admin_balance
+            .checked_sub(amount)
+            .ok_or(ProgramError::InsufficientFunds)?;
+    } else {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    ico_state.sale_supply = ico_state
+        .sale_supply
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    ico_state.total_price_earned = ico_state
+        .total_price_earned
+        .checked_add(total_cost)
+        .ok_or(ICOError::MathOverflow)?;
+    if ico_state.hard_cap_reached() {
+        msg!("Hard cap reached, closing purchases");
+        ico_state.sold_out = true;
+    }
+
+    log_purchase_event(
+        "SALE",
+        buyer_account_info,
+        buyer_account.key,
+        amount,
+        0,
+        total_cost,
+    );
+
+    log_compute_units();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::{
+        entrypoint::SUCCESS,
+        instruction::Instruction,
+        message::Message,
+        program_error::UNSUPPORTED_SYSVAR,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+        system_program,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::sync::Once;
+
+    const ADMIN_SUPPLY: u64 = 10_000;
+    const PRE_SALE_TIME: i64 = 50;
+    const SALE_TIME: i64 = 150;
+    const ENDED_TIME: i64 = 250;
+    const BUYER_LAMPORTS: u64 = 1_000_000_000;
+
+    // The syscall stubs are shared by every test thread, so whatever a test
+    // controls or inspects lives in thread locals.
+    thread_local! {
+        static UNIX_TIME: Cell<Option<i64>> = const { Cell::new(Some(0)) };
+        static SLOT: Cell<u64> = const { Cell::new(0) };
+        static RETURN_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static LOGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        static COMPUTE_UNIT_LOGS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    struct TestSyscalls;
+
+    impl SyscallStubs for TestSyscalls {
+        fn sol_log(&self, message: &str) {
+            LOGS.with(|logs| logs.borrow_mut().push(message.to_string()));
+        }
+
+        fn sol_log_compute_units(&self) {
+            COMPUTE_UNIT_LOGS.with(|count| count.set(count.get() + 1));
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            match UNIX_TIME.with(Cell::get) {
+                Some(unix_timestamp) => {
+                    let clock = Clock {
+                        slot: SLOT.with(Cell::get),
+                        unix_timestamp,
+                        ..Clock::default()
+                    };
+                    unsafe { *(var_addr as *mut Clock) = clock };
+                    SUCCESS
+                }
+                None => UNSUPPORTED_SYSVAR,
+            }
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_set_return_data(&mut self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+        }
+    }
+
+    fn setup() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscalls));
+        });
+        LOGS.with(|logs| logs.borrow_mut().clear());
+        COMPUTE_UNIT_LOGS.with(|count| count.set(0));
+        RETURN_DATA.with(|return_data| return_data.borrow_mut().clear());
+        set_time(0);
+        set_slot(0);
+    }
+
+    fn set_time(unix_timestamp: i64) {
+        UNIX_TIME.with(|time| time.set(Some(unix_timestamp)));
+    }
+
+    fn set_slot(slot: u64) {
+        SLOT.with(|current_slot| current_slot.set(slot));
+    }
+
+    fn return_data() -> Vec<u8> {
+        RETURN_DATA.with(|return_data| return_data.borrow().clone())
+    }
+
+    fn envelope_payload(kind: u8) -> Vec<u8> {
+        let envelope = ReturnEnvelope::try_from_slice(&return_data()).unwrap();
+        assert_eq!(envelope.kind, kind);
+        envelope.payload
+    }
+
+    fn u64_at(data: &[u8], index: usize) -> u64 {
+        u64::from_le_bytes(data[index * 8..index * 8 + 8].try_into().unwrap())
+    }
+
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        is_signer: bool,
+    }
+
+    impl TestAccount {
+        fn new(lamports: u64, data_len: usize) -> Self {
+            TestAccount {
+                key: Pubkey::new_unique(),
+                owner: system_program::id(),
+                lamports,
+                data: vec![0; data_len],
+                is_signer: true,
+            }
+        }
+
+        fn signer() -> Self {
+            TestAccount::new(BUYER_LAMPORTS, 0)
+        }
+
+        /// A buyer whose data carries the purchase amount.
+        fn buyer(amount: u64) -> Self {
+            let mut buyer = TestAccount::new(BUYER_LAMPORTS, 8);
+            buyer.set_amount(amount);
+            buyer
+        }
+
+        fn set_amount(&mut self, amount: u64) {
+            self.data[..8].copy_from_slice(&amount.to_le_bytes());
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                true,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    fn test_state(admin: &Pubkey) -> ICOAccount {
+        let mut ico_state = ICOAccount {
+            version: CURRENT_VERSION,
+            admin: *admin,
+            total_supply: ADMIN_SUPPLY,
+            pre_sale_price: 10,
+            sale_price: 20,
+            sale_start_time: 100,
+            sale_end_time: 200,
+            pre_sale_supply: 3_000,
+            sale_supply: 7_000,
+            price_denominator: 1,
+            ..ICOAccount::default()
+        };
+        ico_state.balance.push((*admin, ADMIN_SUPPLY));
+        ico_state
+    }
+
+    fn balance_of(ico_state: &ICOAccount, account: &Pubkey) -> u64 {
+        ico_state
+            .balance_index(account)
+            .map_or(0, |index| ico_state.balance[index].1)
+    }
+
+    fn presale_purchase(
+        ico_state: &mut ICOAccount,
+        ico: &mut TestAccount,
+        buyer: &mut TestAccount,
+        amount: u64,
+    ) -> ProgramResult {
+        buyer.set_amount(amount);
+        set_time(PRE_SALE_TIME);
+        let ico_info = ico.info();
+        pre_sale(ico_state, &ico_info, &mut [buyer.info()].iter(), None, None)
+    }
+
+    /// Presale entry registered but not whitelisted yet.
+    fn unlisted_entry(address: &Pubkey) -> PreSaleAccount {
+        PreSaleAccount {
+            address: *address,
+            token_amount: 0,
+            token_price: 10,
+            whitelist_account: false,
+            allocation: 0,
+            refunded: false,
+            kyc_verified: false,
+            tier: TIER_REGULAR,
+            whitelist_expiry: 0,
+            lamports_paid: 0,
+            prorated_out: false,
+        }
+    }
+
+    /// The state account at the canonical PDA, with room to grow.
+    fn ico_account(program_id: &Pubkey, ico_state: &mut ICOAccount) -> TestAccount {
+        let (key, ico_bump) =
+            Pubkey::find_program_address(&[ICO_SEED, ico_state.admin.as_ref()], program_id);
+        ico_state.ico_bump = ico_bump;
+        let mut data = ico_state.try_to_vec().unwrap();
+        data.resize(data.len() + ACCOUNT_SPACE_HEADROOM, 0);
+        TestAccount {
+            key,
+            owner: *program_id,
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            is_signer: false,
+        }
+    }
+
+    /// Runs an instruction with `ico` as the first account, rolling its data
+    /// and lamports back on failure like the runtime does.
+    fn process(
+        program_id: &Pubkey,
+        ico: &mut TestAccount,
+        accounts: &mut [&mut TestAccount],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let (data, lamports) = (ico.data.clone(), ico.lamports);
+        let result = {
+            let mut infos = vec![ico.info()];
+            infos.extend(accounts.iter_mut().map(|account| account.info()));
+            process_instruction(program_id, &infos, instruction_data)
+        };
+        if result.is_err() {
+            ico.data = data;
+            ico.lamports = lamports;
+        }
+        result
+    }
+
+    fn read_state(ico: &TestAccount) -> ICOAccount {
+        ICOAccount::deserialize(&mut &ico.data[..]).unwrap()
+    }
+
+    fn sale_purchase(
+        ico_state: &mut ICOAccount,
+        ico: &mut TestAccount,
+        buyer: &mut TestAccount,
+        amount: u64,
+    ) -> ProgramResult {
+        buyer.set_amount(amount);
+        set_time(SALE_TIME);
+        let ico_info = ico.info();
+        sale(ico_state, &ico_info, &mut [buyer.info()].iter())
+    }
+
+    #[test]
+    fn remaining_allocation_after_partial_purchases() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 100, false).unwrap();
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 30).unwrap();
+        remaining_allocation(&ico_state, &buyer.key).unwrap();
+        assert_eq!(
+            u64_at(&envelope_payload(RETURN_REMAINING_ALLOCATION), 0),
+            70
+        );
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 70).unwrap();
+        remaining_allocation(&ico_state, &buyer.key).unwrap();
+        assert_eq!(u64_at(&envelope_payload(RETURN_REMAINING_ALLOCATION), 0), 0);
+
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut buyer, 1),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn remaining_allocation_requires_a_presale_entry() {
+        setup();
+        let ico_state = test_state(&Pubkey::new_unique());
+        assert_eq!(
+            remaining_allocation(&ico_state, &Pubkey::new_unique()),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn fractional_costs_round_up_by_default() {
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.price_denominator = 3;
+        assert_eq!(ico_state.rounding_mode, RoundingMode::Ceil);
+        assert_eq!(ico_state.total_cost(1, 1).unwrap(), 1);
+        assert_eq!(ico_state.total_cost(4, 2).unwrap(), 3);
+        assert_eq!(ico_state.total_cost(3, 2).unwrap(), 2);
+    }
+
+    #[test]
+    fn fractional_costs_round_down_in_floor_mode() {
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.price_denominator = 3;
+        ico_state.rounding_mode = RoundingMode::Floor;
+        assert_eq!(ico_state.total_cost(1, 1).unwrap(), 0);
+        assert_eq!(ico_state.total_cost(4, 2).unwrap(), 2);
+        assert_eq!(ico_state.total_cost(3, 2).unwrap(), 2);
+    }
+
+    fn v1_state(admin: &Pubkey, buyer: &Pubkey) -> ICOAccountV1 {
+        ICOAccountV1 {
+            total_supply: ADMIN_SUPPLY,
+            admin: *admin,
+            balance: vec![(*admin, 9_000), (*buyer, 1_000)],
+            pre_sale_price: 10,
+            pre_sale_limit: 0,
+            sale_price: 20,
+            sale_limit: 0,
+            sale_start_time: 100,
+            sale_end_time: 200,
+            total_price_earned: 18_000,
+            pre_sale_account: vec![PreSaleAccountV1 {
+                address: *buyer,
+                token_amount: 200,
+                token_price: 10,
+                whitelist_account: true,
+            }],
+            sale_account: vec![SaleAccountV1 {
+                address: *buyer,
+                token_amount: 800,
+                token_price: 20,
+            }],
+        }
+    }
+
+    /// Program-owned but still zeroed PDA, as left by a create_account the
+    /// syscall stubs can't perform.
+    fn empty_pda(program_id: &Pubkey, admin: &Pubkey, len: usize) -> TestAccount {
+        let (key, _) = Pubkey::find_program_address(&[ICO_SEED, admin.as_ref()], program_id);
+        TestAccount {
+            key,
+            owner: *program_id,
+            lamports: Rent::default().minimum_balance(len),
+            data: vec![0; len],
+            is_signer: false,
+        }
+    }
+
+    fn migrate(
+        program_id: &Pubkey,
+        old: &mut TestAccount,
+        admin: &mut TestAccount,
+        pda: &mut TestAccount,
+    ) -> ProgramResult {
+        let mut payer = TestAccount::new(BUYER_LAMPORTS, 0);
+        let mut system = TestAccount::new(1, 0);
+        system.key = system_program::id();
+        let accounts = [
+            old.info(),
+            admin.info(),
+            payer.info(),
+            pda.info(),
+            system.info(),
+        ];
+        process_instruction(program_id, &accounts, &[6, 1])
+    }
+
+    #[test]
+    fn migrates_a_v1_account_to_the_pda() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::new(BUYER_LAMPORTS, 0);
+        let buyer = Pubkey::new_unique();
+        let mut old = TestAccount::new(5_000_000, 0);
+        old.owner = program_id;
+        old.data = v1_state(&admin.key, &buyer).try_to_vec().unwrap();
+        let mut pda = empty_pda(&program_id, &admin.key, 2_048);
+        let pda_lamports = pda.lamports;
+
+        migrate(&program_id, &mut old, &mut admin, &mut pda).unwrap();
+
+        let migrated = read_state(&pda);
+        assert_eq!(migrated.version, CURRENT_VERSION);
+        assert_eq!(migrated.admin, admin.key);
+        assert!(!migrated.paused);
+        assert_eq!(migrated.price_denominator, 1);
+        assert_eq!(migrated.pre_sale_supply, 9_000);
+        assert_eq!(migrated.sale_supply, 0);
+        assert_eq!(migrated.total_price_earned, 18_000);
+        assert_eq!(migrated.pre_sale_account[0].lamports_paid, 2_000);
+        assert_eq!(migrated.sale_account[0].lamports_paid, 16_000);
+        migrated.check_ico_address(&program_id, &pda.key).unwrap();
+        assert_eq!(pda.lamports, pda_lamports + 5_000_000);
+        assert_eq!(old.lamports, 0);
+        assert!(old.data.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn migration_requires_the_admin_signature() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let mut impostor = TestAccount::signer();
+        let mut old = TestAccount::new(5_000_000, 0);
+        old.owner = program_id;
+        old.data = v1_state(&admin, &Pubkey::new_unique())
+            .try_to_vec()
+            .unwrap();
+        let mut pda = empty_pda(&program_id, &admin, 2_048);
+
+        assert_eq!(
+            migrate(&program_id, &mut old, &mut impostor, &mut pda),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn migration_does_not_overwrite_an_initialized_pda() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::new(BUYER_LAMPORTS, 0);
+        let mut old = TestAccount::new(5_000_000, 0);
+        old.owner = program_id;
+        old.data = v1_state(&admin.key, &Pubkey::new_unique())
+            .try_to_vec()
+            .unwrap();
+        let mut pda = ico_account(&program_id, &mut test_state(&admin.key));
+
+        assert_eq!(
+            migrate(&program_id, &mut old, &mut admin, &mut pda),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+    }
+
+    #[test]
+    fn presale_purchase_at_and_above_the_per_transaction_maximum() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.max_buy_per_tx = 50;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut buyer, 51),
+            Err(ICOError::MaxBuyPerTxExceeded.into())
+        );
+    }
+
+    #[test]
+    fn sale_purchase_at_and_above_the_per_transaction_maximum() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.max_buy_per_tx = 50;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 51),
+            Err(ICOError::MaxBuyPerTxExceeded.into())
+        );
+    }
+
+    #[test]
+    fn balance_commitment_changes_after_a_mint() {
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        update_balance_commitment(&mut ico_state).unwrap();
+        let before = ico_state.balance_commitment;
+        assert_ne!(before, [0; 32]);
+
+        update_balance_commitment(&mut ico_state).unwrap();
+        assert_eq!(ico_state.balance_commitment, before);
+
+        mint_tokens(&mut ico_state, &Pubkey::new_unique(), 5).unwrap();
+        update_balance_commitment(&mut ico_state).unwrap();
+        assert_ne!(ico_state.balance_commitment, before);
+        assert_eq!(
+            ico_state.balance_commitment,
+            compute_balance_commitment(&ico_state)
+        );
+    }
+
+    #[test]
+    fn first_presale_purchase_creates_the_balance_entry() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        assert!(ico_state.balance_index(&buyer.key).is_err());
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40).unwrap();
+        assert_eq!(balance_of(&ico_state, &buyer.key), 40);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY - 40);
+        assert_eq!(ico.lamports, 400);
+        assert_eq!(buyer.lamports, BUYER_LAMPORTS - 400);
+    }
+
+    #[test]
+    fn presale_purchase_requires_registration_when_configured() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.require_balance_registration = true;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40),
+            Err(ICOError::BalanceNotRegistered.into())
+        );
+        register_balance(&mut ico_state, &buyer.info()).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40).unwrap();
+        assert_eq!(balance_of(&ico_state, &buyer.key), 40);
+    }
+
+    #[test]
+    fn supply_info_after_mints_and_sales() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        mint_tokens(&mut ico_state, &Pubkey::new_unique(), 100).unwrap();
+        mint_tokens(&mut ico_state, &buyer.key, 50).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 30).unwrap();
+
+        supply_info(&ico_state).unwrap();
+        let payload = envelope_payload(RETURN_SUPPLY_INFO);
+        assert_eq!(u64_at(&payload, 0), ADMIN_SUPPLY + 150);
+        assert_eq!(u64_at(&payload, 1), 180);
+    }
+
+    #[test]
+    fn supply_info_before_initialization() {
+        setup();
+        assert_eq!(
+            supply_info(&ICOAccount::default()),
+            Err(ProgramError::UninitializedAccount)
+        );
+        assert!(return_data().is_empty());
+    }
+
+    #[test]
+    fn delegated_whitelister_can_whitelist() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut whitelister = TestAccount::signer();
+        let mut participant = TestAccount::signer();
+        let other = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin.key);
+        ico_state
+            .pre_sale_account
+            .push(unlisted_entry(&participant.key));
+        ico_state.pre_sale_account.push(unlisted_entry(&other));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut whitelister],
+            &[10],
+        )
+        .unwrap();
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut participant, &mut whitelister],
+            &[4],
+        )
+        .unwrap();
+        let mut batch = vec![55];
+        batch.extend_from_slice(other.as_ref());
+        process(&program_id, &mut ico, &mut [&mut whitelister], &batch).unwrap();
+
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.whitelister, whitelister.key);
+        assert!(ico_state.is_whitelisted(&participant.key));
+        assert!(ico_state.is_whitelisted(&other));
+    }
+
+    #[test]
+    fn random_key_cannot_whitelist() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut random = TestAccount::signer();
+        let mut participant = TestAccount::signer();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.whitelister = Pubkey::new_unique();
+        ico_state
+            .pre_sale_account
+            .push(unlisted_entry(&participant.key));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut participant, &mut random],
+                &[4],
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        let mut batch = vec![55];
+        batch.extend_from_slice(participant.key.as_ref());
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut random], &batch),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert!(!read_state(&ico).is_whitelisted(&participant.key));
+    }
+
+    #[test]
+    fn cancelled_ico_refunds_two_buyers_once() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut first = TestAccount::buyer(0);
+        let mut second = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &first.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut first, 100).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut second, 50).unwrap();
+        assert_eq!(ico.lamports, 2_000);
+
+        let ico_info = ico.info();
+        let admin_info = admin.info();
+        let first_info = first.info();
+        let second_info = second.info();
+        assert_eq!(
+            claim_refund(&mut ico_state, &ico_info, &first_info, &first_info),
+            Err(ICOError::NotCancelled.into())
+        );
+        cancel_ico(&mut ico_state, &admin_info).unwrap();
+
+        claim_refund(&mut ico_state, &ico_info, &first_info, &first_info).unwrap();
+        claim_refund(&mut ico_state, &ico_info, &second_info, &admin_info).unwrap();
+        assert_eq!(first_info.lamports(), BUYER_LAMPORTS);
+        assert_eq!(second_info.lamports(), BUYER_LAMPORTS);
+        assert_eq!(ico_info.lamports(), 0);
+        assert_eq!(
+            claim_refund(&mut ico_state, &ico_info, &first_info, &first_info),
+            Err(ICOError::AlreadyRefunded.into())
+        );
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY);
+    }
+
+    #[test]
+    fn refund_claim_needs_the_buyer_or_admin() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let mut stranger = TestAccount::signer();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        ico_state.cancelled = true;
+
+        assert_eq!(
+            claim_refund(&mut ico_state, &ico.info(), &buyer.info(), &stranger.info()),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn current_unix_time_rejects_negative_timestamps() {
+        setup();
+        set_time(0);
+        assert_eq!(current_unix_time(), Ok(0));
+        set_time(i64::MAX);
+        assert_eq!(current_unix_time(), Ok(i64::MAX as u64));
+        set_time(-1);
+        assert_eq!(current_unix_time(), Err(ICOError::InvalidTimestamp.into()));
+        set_time(i64::MIN);
+        assert_eq!(current_unix_time(), Err(ICOError::InvalidTimestamp.into()));
+    }
+
+    fn airdrop(beneficiary: &Pubkey, total_amount: u64) -> VestingSchedule {
+        VestingSchedule {
+            beneficiary: *beneficiary,
+            total_amount,
+            claimed_amount: 0,
+            start_time: 0,
+            cliff_time: 0,
+            end_time: 100,
+        }
+    }
+
+    #[test]
+    fn airdrop_claims_before_and_after_the_deadline() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.claim_deadline = 300;
+        let mut beneficiary = TestAccount::signer();
+        mint_vested_tokens(&mut ico_state, airdrop(&beneficiary.key, 100)).unwrap();
+
+        set_time(50);
+        claim_airdrop(&mut ico_state, &beneficiary.info()).unwrap();
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 50);
+        set_time(300);
+        claim_airdrop(&mut ico_state, &beneficiary.info()).unwrap();
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 100);
+
+        mint_vested_tokens(&mut ico_state, airdrop(&beneficiary.key, 10)).unwrap();
+        set_time(301);
+        assert_eq!(
+            claim_airdrop(&mut ico_state, &beneficiary.info()),
+            Err(ICOError::ClaimDeadlinePassed.into())
+        );
+    }
+
+    #[test]
+    fn unclaimed_airdrops_are_swept_after_the_deadline() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.claim_deadline = 300;
+        let mut beneficiary = TestAccount::signer();
+        mint_vested_tokens(&mut ico_state, airdrop(&beneficiary.key, 100)).unwrap();
+        set_time(50);
+        claim_airdrop(&mut ico_state, &beneficiary.info()).unwrap();
+
+        set_time(300);
+        assert_eq!(
+            sweep_unclaimed_airdrops(&mut ico_state, &admin.info()),
+            Err(ICOError::ClaimWindowOpen.into())
+        );
+        set_time(301);
+        sweep_unclaimed_airdrops(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY + 50);
+        assert_eq!(ico_state.total_supply, ADMIN_SUPPLY + 100);
+        assert_eq!(
+            sweep_unclaimed_airdrops(&mut ico_state, &admin.info()),
+            Err(ICOError::NothingToClaim.into())
+        );
+    }
+
+    #[test]
+    fn supply_cap_can_only_increase() {
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        increase_supply(&mut ico_state, &admin.info(), 12_000).unwrap();
+        assert_eq!(ico_state.total_supply, 12_000);
+        assert_eq!(balance_of(&ico_state, &admin.key), 12_000);
+
+        for new_supply in [12_000, 11_000] {
+            assert_eq!(
+                increase_supply(&mut ico_state, &admin.info(), new_supply),
+                Err(ICOError::SupplyDecrease.into())
+            );
+        }
+        assert_eq!(
+            increase_supply(&mut ico_state, &TestAccount::signer().info(), 13_000),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(ico_state.total_supply, 12_000);
+    }
+
+    #[test]
+    fn balance_entries_stay_sorted() {
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let holders: Vec<Pubkey> = (0..8).map(|_| Pubkey::new_unique()).collect();
+        for (amount, holder) in holders.iter().rev().enumerate() {
+            *ico_state.balance_entry(holder) += amount as u64 + 1;
+        }
+
+        assert!(ico_state
+            .balance
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0));
+        for (amount, holder) in holders.iter().rev().enumerate() {
+            assert_eq!(balance_of(&ico_state, holder), amount as u64 + 1);
+        }
+        assert!(ico_state.balance_mut(&Pubkey::new_unique()).is_none());
+    }
+
+    #[test]
+    fn top_holders_by_amount_with_ties_by_pubkey() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        let mut tied = [Pubkey::new_unique(), Pubkey::new_unique()];
+        tied.sort();
+        let (small, large) = (Pubkey::new_unique(), Pubkey::new_unique());
+        for (holder, amount) in [(tied[1], 50), (small, 10), (tied[0], 50), (large, 70)] {
+            *ico_state.balance_entry(&holder) = amount;
+        }
+
+        top_holders(&ico_state, 4).unwrap();
+        let holders =
+            Vec::<(Pubkey, u64)>::try_from_slice(&envelope_payload(RETURN_TOP_HOLDERS)).unwrap();
+        assert_eq!(
+            holders,
+            vec![
+                (admin, ADMIN_SUPPLY),
+                (large, 70),
+                (tied[0], 50),
+                (tied[1], 50)
+            ]
+        );
+        assert_eq!(
+            top_holders(&ico_state, MAX_TOP_HOLDERS + 1),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn blocked_address_cannot_buy_or_transfer() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.transfers_enabled = true;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        let recipient = Pubkey::new_unique();
+
+        block_address(&mut ico_state, &admin.info(), &buyer.key).unwrap();
+        let blocked = Err(ICOError::AddressBlocked.into());
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10),
+            blocked
+        );
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10),
+            blocked
+        );
+        assert_eq!(
+            transfer_tokens(&mut ico_state, &buyer.info(), &recipient, 5),
+            blocked
+        );
+        assert_eq!(
+            transfer_tokens(&mut ico_state, &admin.info(), &buyer.key, 5),
+            blocked
+        );
+
+        unblock_address(&mut ico_state, &admin.info(), &buyer.key).unwrap();
+        transfer_tokens(&mut ico_state, &buyer.info(), &recipient, 5).unwrap();
+        assert_eq!(balance_of(&ico_state, &recipient), 5);
+    }
+
+    fn register_instruction(allocation: u64, overwrite: bool) -> Vec<u8> {
+        let mut instruction_data = vec![17];
+        instruction_data.extend_from_slice(&allocation.to_le_bytes());
+        instruction_data.push(overwrite as u8);
+        instruction_data
+    }
+
+    #[test]
+    fn registers_whitelists_and_allocates_in_one_instruction() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut participant = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut participant, &mut admin],
+            &register_instruction(500, false),
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.pre_sale_account.len(), 1);
+        assert!(ico_state.is_whitelisted(&participant.key));
+        assert_eq!(ico_state.pre_sale_account[0].allocation, 500);
+
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut participant, &mut admin],
+                &register_instruction(800, false),
+            ),
+            Err(ICOError::AlreadyRegistered.into())
+        );
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut participant, &mut admin],
+            &register_instruction(800, true),
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.pre_sale_account.len(), 1);
+        assert_eq!(ico_state.pre_sale_account[0].allocation, 800);
+    }
+
+    fn mint_instruction(amount: u64, key: Option<[u8; IDEMPOTENCY_KEY_LEN]>) -> Vec<u8> {
+        let mut instruction_data = vec![1];
+        instruction_data.extend_from_slice(&amount.to_le_bytes());
+        if let Some(key) = key {
+            instruction_data.extend_from_slice(&key);
+        }
+        instruction_data
+    }
+
+    #[test]
+    fn repeated_mint_with_one_idempotency_key_applies_once() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        for _ in 0..2 {
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin, &mut recipient],
+                &mint_instruction(100, Some([7; IDEMPOTENCY_KEY_LEN])),
+            )
+            .unwrap();
+        }
+        let ico_state = read_state(&ico);
+        assert_eq!(balance_of(&ico_state, &recipient.key), 100);
+        assert_eq!(ico_state.total_mints, 1);
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut recipient],
+            &mint_instruction(100, Some([8; IDEMPOTENCY_KEY_LEN])),
+        )
+        .unwrap();
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut recipient],
+            &mint_instruction(100, None),
+        )
+        .unwrap();
+        assert_eq!(balance_of(&read_state(&ico), &recipient.key), 300);
+    }
+
+    #[test]
+    fn idempotency_keys_are_remembered_in_a_ring_buffer() {
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        for key in 1..=IDEMPOTENCY_KEY_SLOTS as u8 {
+            assert!(!ico_state.check_idempotency_key([key; IDEMPOTENCY_KEY_LEN]));
+        }
+        assert!(ico_state.check_idempotency_key([1; IDEMPOTENCY_KEY_LEN]));
+
+        // One more key evicts the oldest.
+        assert!(!ico_state.check_idempotency_key([0xff; IDEMPOTENCY_KEY_LEN]));
+        assert!(!ico_state.check_idempotency_key([1; IDEMPOTENCY_KEY_LEN]));
+    }
+
+    #[test]
+    fn unsold_presale_tokens_roll_into_the_sale() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 1_000).unwrap();
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 100).unwrap();
+        assert_eq!(ico_state.pre_sale_supply, 0);
+        assert_eq!(ico_state.sale_supply, 7_000 + 2_000 - 100);
+    }
+
+    #[test]
+    fn admin_closes_the_presale_once_it_ended() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        set_time(PRE_SALE_TIME);
+        assert_eq!(
+            close_pre_sale(&mut ico_state, &admin.info()),
+            Err(ICOError::PresaleActive.into())
+        );
+        set_time(SALE_TIME);
+        close_pre_sale(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(ico_state.pre_sale_supply, 0);
+        assert_eq!(ico_state.sale_supply, 10_000);
+    }
+
+    #[test]
+    fn prices_per_lot_of_tokens() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.sale_price = 3;
+        ico_state.price_denominator = 1_000;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+
+        assert_eq!(ico_state.total_cost(1_000, 3).unwrap(), 3);
+        assert_eq!(ico_state.total_cost(1, 3).unwrap(), 1);
+        ico_state.rounding_mode = RoundingMode::Floor;
+        assert_eq!(ico_state.total_cost(1, 3).unwrap(), 0);
+        assert_eq!(ico_state.total_cost(2_500, 3).unwrap(), 7);
+        assert_eq!(
+            ico_state.total_cost(u64::MAX, u64::MAX),
+            Err(ICOError::MathOverflow.into())
+        );
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 2_000).unwrap();
+        assert_eq!(ico.lamports, 6);
+        assert_eq!(ico_state.total_price_earned, 6);
+    }
+
+    #[test]
+    fn health_check_detects_corrupted_state() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 100).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 100).unwrap();
+        health_check(&ico_state).unwrap();
+        assert_eq!(return_data(), vec![0]);
+
+        ico_state.total_supply += 1;
+        ico_state.total_price_earned += 1;
+        ico_state.sale_account[0].token_amount += 1;
+        health_check(&ico_state).unwrap();
+        assert_eq!(
+            return_data(),
+            vec![
+                HEALTH_SUPPLY_MISMATCH | HEALTH_RECORDS_EXCEED_BALANCES | HEALTH_PROCEEDS_MISMATCH
+            ]
+        );
+
+        ico_state.balance.reverse();
+        health_check(&ico_state).unwrap();
+        assert_ne!(return_data()[0] & HEALTH_BALANCES_UNSORTED, 0);
+    }
+
+    #[test]
+    fn live_sale_can_only_be_extended() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        set_time(PRE_SALE_TIME);
+        assert_eq!(
+            extend_sale(&mut ico_state, &admin.info(), 300),
+            Err(ICOError::SaleNotActive.into())
+        );
+        set_time(SALE_TIME);
+        extend_sale(&mut ico_state, &admin.info(), 300).unwrap();
+        assert_eq!(ico_state.sale_end_time, 300);
+        for new_end in [300, 250] {
+            assert_eq!(
+                extend_sale(&mut ico_state, &admin.info(), new_end),
+                Err(ICOError::InvalidSaleEnd.into())
+            );
+        }
+
+        set_time(300);
+        assert_eq!(
+            extend_sale(&mut ico_state, &admin.info(), 400),
+            Err(ICOError::SaleNotActive.into())
+        );
+        assert_eq!(ico_state.sale_end_time, 300);
+    }
+
+    fn init_params() -> InitParams {
+        InitParams {
+            total_supply: ADMIN_SUPPLY,
+            pre_sale_price: 10,
+            pre_sale_limit: 0,
+            sale_price: 20,
+            sale_limit: 0,
+            sale_start_time: 100,
+            sale_end_time: 200,
+            pre_sale_supply: 3_000,
+            sale_supply: 7_000,
+        }
+    }
+
+    fn init_instruction(params: &InitParams) -> Vec<u8> {
+        let mut instruction_data = vec![0];
+        instruction_data.extend_from_slice(&params.try_to_vec().unwrap());
+        instruction_data
+    }
+
+    #[test]
+    fn failed_initialization_persists_no_state() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        admin.is_signer = false;
+        let mut ico = empty_pda(&program_id, &admin.key, 2_048);
+
+        let accounts = [ico.info(), admin.info()];
+        assert_eq!(
+            process_instruction(&program_id, &accounts, &init_instruction(&init_params())),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        drop(accounts);
+        assert!(!read_state(&ico).is_initialized());
+
+        let mut ico_state = ICOAccount::default();
+        let mut params = init_params();
+        params.pre_sale_price = 0;
+        admin.is_signer = true;
+        assert_eq!(
+            intialize_ico(
+                &program_id,
+                &mut ico_state,
+                &ico.info(),
+                &mut [admin.info()].iter(),
+                &init_instruction(&params),
+            ),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert!(!ico_state.is_initialized());
+        assert!(ico_state.balance.is_empty());
+    }
+
+    #[test]
+    fn initializes_the_state_at_the_pda() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut system = TestAccount::new(1, 0);
+        system.key = system_program::id();
+        let mut ico = empty_pda(&program_id, &admin.key, 2_048);
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut system],
+            &init_instruction(&init_params()),
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.version, CURRENT_VERSION);
+        assert_eq!(ico_state.admin, admin.key);
+        assert_eq!(ico_state.balance, vec![(admin.key, ADMIN_SUPPLY)]);
+        assert_eq!(ico_state.sale_supply, 7_000);
+        assert!(!ico_state.locked);
+
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin, &mut system],
+                &init_instruction(&init_params()),
+            ),
+            Err(ProgramError::AccountAlreadyInitialized)
+        );
+    }
+
+    #[test]
+    fn decoder_rejects_the_fuzzed_panics() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        for instruction_data in [&[][..], &[1, 0, 0, 0][..]] {
+            assert_eq!(
+                process(
+                    &program_id,
+                    &mut ico,
+                    &mut [&mut admin, &mut recipient],
+                    instruction_data,
+                ),
+                Err(ProgramError::InvalidInstructionData)
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_never_panic() {
+        setup();
+        set_time(PRE_SALE_TIME);
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut participant = TestAccount::buyer(1);
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        for instruction in 0..=u8::MAX {
+            for len in 0..40 {
+                let mut instruction_data = vec![0xff; len + 1];
+                instruction_data[0] = instruction;
+                let _ = process(
+                    &program_id,
+                    &mut ico,
+                    &mut [&mut admin, &mut participant],
+                    &instruction_data,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn purchase_cannot_leave_the_buyer_below_rent_exemption() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let rent_minimum = Rent::default().minimum_balance(buyer.data.len());
+
+        buyer.lamports = rent_minimum + 199;
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10),
+            Err(ICOError::BelowRentExemption.into())
+        );
+        buyer.lamports = 199;
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10),
+            Err(ProgramError::InsufficientFunds)
+        );
+
+        buyer.lamports = rent_minimum + 200;
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        assert_eq!(buyer.lamports, rent_minimum);
+    }
+
+    #[test]
+    fn phase_sold_amounts_after_mixed_activity() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut first = TestAccount::buyer(0);
+        let mut second = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &first.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &second.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut first, 30).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut second, 20).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut first, 5).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut first, 40).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut second, 7).unwrap();
+        mint_tokens(&mut ico_state, &first.key, 1_000).unwrap();
+
+        phase_sold_amounts(&ico_state).unwrap();
+        let sold = return_data();
+        assert_eq!(u64_at(&sold, 0), 55);
+        assert_eq!(u64_at(&sold, 1), 47);
+    }
+
+    #[test]
+    fn removes_only_presale_accounts_without_purchases() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let unused = Pubkey::new_unique();
+        register_whitelisted(&mut ico_state, &unused, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+
+        remove_presale_account(&mut ico_state, &admin.info(), &unused).unwrap();
+        assert!(!ico_state.is_whitelisted(&unused));
+        assert_eq!(
+            remove_presale_account(&mut ico_state, &admin.info(), &unused),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            remove_presale_account(&mut ico_state, &admin.info(), &buyer.key),
+            Err(ICOError::ParticipantHasPurchased.into())
+        );
+        assert_eq!(ico_state.pre_sale_account.len(), 1);
+    }
+
+    #[test]
+    fn whitelist_requirement_matrix() {
+        setup();
+        for (requirement, pre_sale_open, sale_open) in [
+            (WhitelistRequirement::None, true, true),
+            (WhitelistRequirement::PreSaleOnly, false, true),
+            (WhitelistRequirement::SaleOnly, true, false),
+            (WhitelistRequirement::Both, false, false),
+        ] {
+            let mut ico_state = test_state(&Pubkey::new_unique());
+            ico_state.whitelist_requirement = requirement;
+            let mut ico = TestAccount::new(0, 0);
+            let mut listed = TestAccount::buyer(0);
+            let mut unlisted = TestAccount::buyer(0);
+            register_whitelisted(&mut ico_state, &listed.key, 0, false).unwrap();
+
+            presale_purchase(&mut ico_state, &mut ico, &mut listed, 10).unwrap();
+            assert_eq!(
+                presale_purchase(&mut ico_state, &mut ico, &mut unlisted, 10).is_ok(),
+                pre_sale_open,
+                "{:?}",
+                requirement
+            );
+            if pre_sale_open {
+                let record = ico_state
+                    .pre_sale_account
+                    .iter()
+                    .find(|pre_sale_account| pre_sale_account.address == unlisted.key)
+                    .unwrap();
+                assert!(!record.whitelist_account);
+                assert_eq!(
+                    (
+                        record.token_amount,
+                        record.token_price,
+                        record.lamports_paid
+                    ),
+                    (10, 10, 100)
+                );
+            }
+            sale_purchase(&mut ico_state, &mut ico, &mut listed, 10).unwrap();
+            assert_eq!(
+                sale_purchase(&mut ico_state, &mut ico, &mut unlisted, 10).is_ok(),
+                sale_open,
+                "{:?}",
+                requirement
+            );
+
+            // Whatever the unlisted buyer paid in either phase comes back.
+            ico_state.cancelled = true;
+            let unlisted_info = unlisted.info();
+            let refund = claim_refund(&mut ico_state, &ico.info(), &unlisted_info, &unlisted_info);
+            assert_eq!(
+                refund.is_ok(),
+                pre_sale_open || sale_open,
+                "{:?}",
+                requirement
+            );
+            assert_eq!(unlisted.lamports, BUYER_LAMPORTS);
+        }
+        assert_eq!(
+            WhitelistRequirement::default(),
+            WhitelistRequirement::PreSaleOnly
+        );
+    }
+
+    #[test]
+    fn duplicate_and_unsorted_balances_are_normalized() {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.balance = vec![(second, 1), (first, 2), (second, 3)];
+
+        ico_state.normalize_balances().unwrap();
+        let mut expected = vec![(first, 2), (second, 4)];
+        expected.sort();
+        assert_eq!(ico_state.balance, expected);
+    }
+
+    #[test]
+    fn instructions_coalesce_duplicated_balances_before_running() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.balance.push((recipient.key, 5));
+        ico_state.balance.push((recipient.key, 7));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut recipient],
+            &mint_instruction(1, None),
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.balance.len(), 2);
+        assert_eq!(balance_of(&ico_state, &recipient.key), 13);
+    }
+
+    /// Root of a two-leaf tree built with sorted pair hashing.
+    fn merkle_root(first: [u8; 32], second: [u8; 32]) -> [u8; 32] {
+        if first <= second {
+            hashv(&[&first, &second]).to_bytes()
+        } else {
+            hashv(&[&second, &first]).to_bytes()
+        }
+    }
+
+    fn merkle_purchase(
+        ico_state: &mut ICOAccount,
+        ico: &mut TestAccount,
+        buyer: &mut TestAccount,
+        amount: u64,
+        merkle_proof: Option<MerkleWhitelistProof>,
+    ) -> ProgramResult {
+        buyer.set_amount(amount);
+        set_time(PRE_SALE_TIME);
+        let ico_info = ico.info();
+        pre_sale(
+            ico_state,
+            &ico_info,
+            &mut [buyer.info()].iter(),
+            merkle_proof,
+            None,
+        )
+    }
+
+    #[test]
+    fn whitelist_root_switches_between_merkle_and_list_mode() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let mut other = TestAccount::buyer(0);
+        let other_leaf = merkle_leaf(&other.key, 50);
+        let root = merkle_root(merkle_leaf(&buyer.key, 100), other_leaf);
+        let proof = |allocation| {
+            Some(MerkleWhitelistProof {
+                allocation,
+                proof: vec![other_leaf],
+            })
+        };
+
+        set_whitelist_root(&mut ico_state, &admin.info(), root).unwrap();
+        assert_eq!(ico_state.whitelist_mode, WhitelistMode::Merkle);
+        assert_eq!(
+            merkle_purchase(&mut ico_state, &mut ico, &mut buyer, 10, None),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert_eq!(
+            merkle_purchase(&mut ico_state, &mut ico, &mut buyer, 10, proof(200)),
+            Err(ICOError::NotWhitelisted.into())
+        );
+        merkle_purchase(&mut ico_state, &mut ico, &mut buyer, 10, proof(100)).unwrap();
+        assert!(ico_state.is_whitelisted(&buyer.key));
+        assert_eq!(ico_state.pre_sale_account[0].allocation, 100);
+
+        set_whitelist_root(&mut ico_state, &admin.info(), [0; 32]).unwrap();
+        assert_eq!(ico_state.whitelist_mode, WhitelistMode::List);
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut other, 10),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn airdrop_vesting_is_claimed_apart_from_purchases() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut buyer = TestAccount::buyer(0);
+        let mut ico_state = test_state(&admin.key);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut TestAccount::new(0, 0), &mut buyer, 30).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        let mut instruction_data = mint_instruction(100, None);
+        for time in [0u64, 0, 100] {
+            instruction_data.extend_from_slice(&time.to_le_bytes());
+        }
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut buyer],
+            &instruction_data,
+        )
+        .unwrap();
+        let mut ico_state = read_state(&ico);
+        assert_eq!(ico_state.airdrop_vesting, vec![airdrop(&buyer.key, 100)]);
+        assert_eq!(balance_of(&ico_state, &buyer.key), 30);
+
+        buyer.is_signer = true;
+        set_time(25);
+        claim_airdrop(&mut ico_state, &buyer.info()).unwrap();
+        assert_eq!(balance_of(&ico_state, &buyer.key), 55);
+        assert_eq!(ico_state.purchased_tokens(&buyer.key), Ok(30));
+        assert_eq!(ico_state.airdrop_vesting[0].claimed_amount, 25);
+    }
+
+    #[test]
+    fn purchases_log_compute_units_when_profiling() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        let expected = if cfg!(feature = "profiling") { 2 } else { 0 };
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        assert_eq!(COMPUTE_UNIT_LOGS.with(Cell::get), expected);
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        assert_eq!(COMPUTE_UNIT_LOGS.with(Cell::get), 2 * expected);
+    }
+
+    #[test]
+    fn refund_keeps_the_fee_rounded_down() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.refund_fee_bps = 250;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 21).unwrap();
+        assert_eq!(ico.lamports, 420);
+        ico_state.cancelled = true;
+
+        let buyer_info = buyer.info();
+        claim_refund(&mut ico_state, &ico.info(), &buyer_info, &buyer_info).unwrap();
+        assert_eq!(buyer_info.lamports(), BUYER_LAMPORTS - 10);
+        assert_eq!(ico.lamports, 10);
+        assert_eq!(ico_state.refund_fees_collected, 10);
+    }
+
+    #[test]
+    fn early_bird_bonus_only_inside_the_window() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        ico_state.early_bird_bonus_bps = 1_000;
+        ico_state.early_bird_deadline = PRE_SALE_TIME as u64;
+        let mut ico = TestAccount::new(0, 0);
+        let mut early = TestAccount::buyer(0);
+        let mut late = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &early.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &late.key, 0, false).unwrap();
+
+        presale_purchase(&mut ico_state, &mut ico, &mut early, 40).unwrap();
+        assert_eq!(balance_of(&ico_state, &early.key), 44);
+        assert_eq!(ico_state.bonus_tokens, vec![(early.key, 4)]);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY - 44);
+
+        ico_state.early_bird_deadline = PRE_SALE_TIME as u64 - 1;
+        presale_purchase(&mut ico_state, &mut ico, &mut late, 40).unwrap();
+        assert_eq!(balance_of(&ico_state, &late.key), 40);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY - 84);
+        assert_eq!(ico_state.pre_sale_supply, 3_000 - 80);
+        assert_eq!(ico.lamports, 800);
+    }
+
+    fn logged(fragment: &str) -> bool {
+        LOGS.with(|logs| logs.borrow().iter().any(|log| log.contains(fragment)))
+    }
+
+    #[test]
+    fn unknown_discriminant_is_logged_and_rejected() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico = ico_account(&program_id, &mut test_state(&Pubkey::new_unique()));
+
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [], &[99]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert!(logged("Unknown instruction discriminant: 99"));
+    }
+
+    #[test]
+    fn snapshot_is_reconstructed_from_two_chunks() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        for amount in 1..=3 {
+            mint_tokens(&mut ico_state, &Pubkey::new_unique(), amount).unwrap();
+        }
+
+        export_snapshot(&ico_state, 0, 100).unwrap();
+        let first = return_data();
+        let total_len = u64_at(&first, 0) as usize;
+        assert!(total_len > 100);
+        export_snapshot(&ico_state, 100, 1_000).unwrap();
+        let second = return_data();
+        assert_eq!(u64_at(&second, 0) as usize, total_len);
+
+        let mut encoded = first[8..].to_vec();
+        encoded.extend_from_slice(&second[8..]);
+        assert_eq!(encoded.len(), total_len);
+        let snapshot = ICOSnapshot::try_from_slice(&encoded).unwrap();
+        assert_eq!(snapshot.snapshot_version, SNAPSHOT_VERSION);
+        assert_eq!(snapshot.total_supply, ico_state.total_supply);
+        assert_eq!(snapshot.balances, ico_state.balance);
+        assert_eq!(snapshot.balances.len(), 4);
+    }
+
+    #[test]
+    fn purchases_above_the_kyc_threshold_need_verification() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.kyc_threshold = 100;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 60).unwrap();
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50),
+            Err(ICOError::KycRequired.into())
+        );
+        assert_eq!(balance_of(&ico_state, &buyer.key), 60);
+
+        set_kyc_verified(&mut ico_state, &admin.info(), &buyer.key, true).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        assert_eq!(balance_of(&ico_state, &buyer.key), 110);
+    }
+
+    #[test]
+    fn withdrawable_balance_excludes_the_rent_minimum() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        withdrawable_balance(&ico.info()).unwrap();
+        assert_eq!(u64_at(&return_data(), 0), 0);
+
+        let mut buyer = TestAccount::buyer(0);
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        withdrawable_balance(&ico.info()).unwrap();
+        assert_eq!(u64_at(&return_data(), 0), 1_000);
+    }
+
+    #[test]
+    fn admin_tokens_are_locked_until_the_lockup_ends() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let recipient = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.transfers_enabled = true;
+        ico_state.admin_lockup_until = 200;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+
+        set_time(199);
+        assert_eq!(
+            transfer_tokens(&mut ico_state, &admin.info(), &recipient, 10),
+            Err(ICOError::AdminTokensLocked.into())
+        );
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY - 10);
+
+        set_time(200);
+        transfer_tokens(&mut ico_state, &admin.info(), &recipient, 10).unwrap();
+        assert_eq!(balance_of(&ico_state, &recipient), 10);
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY - 20);
+    }
+
+    #[test]
+    fn contributions_up_to_the_lamport_cap() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.max_contribution = 1_000;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 30).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 20).unwrap();
+        assert_eq!(ico_state.contributed_lamports(&buyer.key), 1_000);
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 1),
+            Err(ICOError::ContributionCapExceeded.into())
+        );
+        assert_eq!(balance_of(&ico_state, &buyer.key), 50);
+        assert_eq!(ico.lamports, 1_000);
+    }
+
+    #[test]
+    fn reconciles_a_corrupted_total_price_earned() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut first = TestAccount::buyer(0);
+        let mut second = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &first.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut first, 30).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut second, 40).unwrap();
+        ico_state.total_price_earned = 1;
+
+        reconcile_total_price_earned(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(ico_state.total_price_earned, 30 * 10 + 40 * 20);
+        assert!(logged("Reconciling total_price_earned: 1 -> 1100"));
+    }
+
+    #[test]
+    fn whitelisting_twice_is_a_logged_no_op() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let address = Pubkey::new_unique();
+        ico_state.pre_sale_account.push(unlisted_entry(&address));
+
+        whitelist_account(&mut ico_state, &address).unwrap();
+        assert!(!logged("Account is already whitelisted"));
+        whitelist_account(&mut ico_state, &address).unwrap();
+        assert!(logged("Account is already whitelisted"));
+        assert!(ico_state.is_whitelisted(&address));
+        assert_eq!(ico_state.total_whitelists, 1);
+    }
+
+    #[test]
+    fn purchases_are_limited_to_the_allowed_bundles() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        set_allowed_amounts(&mut ico_state, &admin.info(), vec![10, 50, 100]).unwrap();
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut buyer, 30),
+            Err(ICOError::AmountNotAllowed.into())
+        );
+        assert_eq!(balance_of(&ico_state, &buyer.key), 50);
+    }
+
+    #[test]
+    fn queued_price_change_waits_for_the_timelock() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        set_time(1_000);
+        queue_action(
+            &mut ico_state,
+            &admin.info(),
+            TimelockAction::SetSalePrice(25),
+        )
+        .unwrap();
+        let eta = 1_000 + TIMELOCK_DELAY;
+        assert_eq!(
+            ico_state.pending_action,
+            Some(PendingAction {
+                action: TimelockAction::SetSalePrice(25),
+                eta,
+            })
+        );
+
+        set_time(eta as i64 - 1);
+        assert_eq!(
+            execute_action(&mut ico_state, &admin.info()),
+            Err(ICOError::TimelockNotElapsed.into())
+        );
+        assert_eq!(ico_state.sale_price, 20);
+
+        set_time(eta as i64);
+        execute_action(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(ico_state.sale_price, 25);
+        assert_eq!(ico_state.pending_action, None);
+        assert_eq!(
+            execute_action(&mut ico_state, &admin.info()),
+            Err(ICOError::NoPendingAction.into())
+        );
+    }
+
+    #[test]
+    fn current_phase_at_several_timestamps() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.pre_sale_start_time = 10;
+
+        for (time, phase) in [
+            (9, SalePhase::NotStarted),
+            (10, SalePhase::PreSale),
+            (99, SalePhase::PreSale),
+            (100, SalePhase::Sale),
+            (199, SalePhase::Sale),
+            (200, SalePhase::Ended),
+        ] {
+            set_time(time);
+            current_phase(&ico_state).unwrap();
+            assert_eq!(return_data(), vec![phase as u8], "at {}", time);
+        }
+    }
+
+    #[test]
+    fn payer_buys_for_a_separate_beneficiary() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut payer = TestAccount::buyer(40);
+        let mut beneficiary = TestAccount::new(0, 0);
+        register_whitelisted(&mut ico_state, &beneficiary.key, 0, false).unwrap();
+        set_time(PRE_SALE_TIME);
+
+        payer.is_signer = false;
+        assert_eq!(
+            pre_sale(
+                &mut ico_state,
+                &ico.info(),
+                &mut [payer.info(), beneficiary.info()].iter(),
+                None,
+                None,
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+
+        payer.is_signer = true;
+        pre_sale(
+            &mut ico_state,
+            &ico.info(),
+            &mut [payer.info(), beneficiary.info()].iter(),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 40);
+        assert_eq!(balance_of(&ico_state, &payer.key), 0);
+        assert_eq!(ico_state.pre_sale_account[0].token_amount, 40);
+        assert_eq!(payer.lamports, BUYER_LAMPORTS - 400);
+        assert_eq!(beneficiary.lamports, 0);
+        assert_eq!(ico.lamports, 400);
+    }
+
+    #[test]
+    fn batch_whitelist_rejects_more_than_the_item_cap() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+        let batch = |len: usize| {
+            let mut instruction_data = vec![55];
+            for _ in 0..len {
+                instruction_data.extend_from_slice(Pubkey::new_unique().as_ref());
+            }
+            instruction_data
+        };
+
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin],
+                &batch(MAX_BATCH_ITEMS + 1)
+            ),
+            Err(ICOError::BatchTooLarge.into())
+        );
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin],
+            &batch(MAX_BATCH_ITEMS),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn total_paid_across_phases_and_prices() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 30).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 5).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 20).unwrap();
+
+        total_paid(&ico_state, &buyer.key).unwrap();
+        assert_eq!(u64_at(&return_data(), 0), 35 * 10 + 20 * 20);
+        total_paid(&ico_state, &Pubkey::new_unique()).unwrap();
+        assert_eq!(u64_at(&return_data(), 0), 0);
+    }
+
+    #[test]
+    fn formats_amounts_for_several_decimals() {
+        assert_eq!(format_token_amount(0, 0), "0");
+        assert_eq!(format_token_amount(1_234, 0), "1234");
+        assert_eq!(format_token_amount(1_234, 2), "12.34");
+        assert_eq!(format_token_amount(5, 3), "0.005");
+        assert_eq!(format_token_amount(0, 9), "0.000000000");
+        assert_eq!(format_token_amount(1_500_000_000, 9), "1.500000000");
+        assert_eq!(format_token_amount(u64::MAX, 18), "18.446744073709551615");
+    }
+
+    #[test]
+    fn format_instruction_returns_the_string() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico = ico_account(&program_id, &mut test_state(&Pubkey::new_unique()));
+        let mut instruction_data = vec![34];
+        instruction_data.extend_from_slice(&1_234_567u64.to_le_bytes());
+        instruction_data.push(6);
+
+        process(&program_id, &mut ico, &mut [], &instruction_data).unwrap();
+        assert_eq!(return_data(), b"1.234567");
+        assert!(logged("1.234567"));
+    }
+
+    #[test]
+    fn buying_the_last_tokens_marks_the_ico_sold_out() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.pre_sale_supply = 0;
+        ico_state.sale_supply = 50;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let mut first = TestAccount::buyer(50);
+        let mut second = TestAccount::buyer(1);
+        set_time(SALE_TIME);
+
+        process(&program_id, &mut ico, &mut [&mut first], &[3]).unwrap();
+        let ico_state = read_state(&ico);
+        assert!(ico_state.sold_out);
+        assert_eq!(ico_state.sale_supply, 0);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut second], &[3]),
+            Err(ICOError::SoldOut.into())
+        );
+        assert!(logged("ICO is sold out"));
+    }
+
+    #[test]
+    fn proceeds_are_split_across_three_recipients() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut recipients = [
+            TestAccount::new(0, 0),
+            TestAccount::new(0, 0),
+            TestAccount::new(0, 0),
+        ];
+        let split: Vec<(Pubkey, u16)> = recipients
+            .iter()
+            .zip([3_333, 3_333, 3_334])
+            .map(|(recipient, bps)| (recipient.key, bps))
+            .collect();
+        let mut short_split = split.clone();
+        short_split[2].1 = 3_333;
+        assert_eq!(
+            set_treasury_split(&mut ico_state, &admin.info(), short_split),
+            Err(ICOError::InvalidTreasurySplit.into())
+        );
+        set_treasury_split(&mut ico_state, &admin.info(), split).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let rent_minimum = ico.lamports;
+        ico.lamports += 1_001;
+
+        set_time(ENDED_TIME);
+        let recipient_infos: Vec<_> = recipients.iter_mut().map(TestAccount::info).collect();
+        withdraw_proceeds(
+            &mut ico_state,
+            &ico.info(),
+            &admin.info(),
+            &mut recipient_infos.iter(),
+        )
+        .unwrap();
+        let shares: Vec<u64> = recipient_infos.iter().map(|info| info.lamports()).collect();
+        assert_eq!(shares, vec![333, 333, 335]);
+        assert_eq!(ico.lamports, rent_minimum);
+        assert_eq!(ico_state.total_withdrawn, 1_001);
+    }
+
+    fn vesting_status_at(ico_state: &ICOAccount, participant: &Pubkey, time: i64) -> Vec<u64> {
+        set_time(time);
+        vesting_status(ico_state, participant).unwrap();
+        let data = return_data();
+        (0..4).map(|index| u64_at(&data, index)).collect()
+    }
+
+    #[test]
+    fn vesting_status_at_partial_and_full_unlock() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut beneficiary = TestAccount::signer();
+        mint_vested_tokens(&mut ico_state, airdrop(&beneficiary.key, 100)).unwrap();
+
+        assert_eq!(
+            vesting_status_at(&ico_state, &Pubkey::new_unique(), 50),
+            vec![0, 0, 0, 0]
+        );
+        assert_eq!(
+            vesting_status_at(&ico_state, &beneficiary.key, 40),
+            vec![100, 0, 40, 100]
+        );
+        claim_airdrop(&mut ico_state, &beneficiary.info()).unwrap();
+        assert_eq!(
+            vesting_status_at(&ico_state, &beneficiary.key, 60),
+            vec![100, 40, 20, 100]
+        );
+        assert_eq!(
+            vesting_status_at(&ico_state, &beneficiary.key, 100),
+            vec![100, 40, 60, 0]
+        );
+    }
+
+    #[test]
+    fn zero_supply_is_rejected_and_treated_as_sold_out() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico = empty_pda(&program_id, &admin.key, 2_048);
+        let mut params = init_params();
+        params.total_supply = 0;
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin],
+                &init_instruction(&params)
+            ),
+            Err(ICOError::ZeroSupply.into())
+        );
+        let mut params = init_params();
+        params.pre_sale_supply = 0;
+        params.sale_supply = 0;
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin],
+                &init_instruction(&params)
+            ),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert!(!read_state(&ico).is_initialized());
+
+        let mut ico_state = test_state(&admin.key);
+        ico_state.total_supply = 0;
+        ico_state.refresh_sold_out();
+        assert!(ico_state.sold_out);
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let mut buyer = TestAccount::buyer(10);
+        set_time(SALE_TIME);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[3]),
+            Err(ICOError::SoldOut.into())
+        );
+    }
+
+    #[test]
+    fn presale_tokens_are_flip_locked() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.transfers_enabled = true;
+        ico_state.flip_lock_seconds = 200;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let recipient = Pubkey::new_unique();
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+
+        set_time(PRE_SALE_TIME + 199);
+        transfer_tokens(&mut ico_state, &buyer.info(), &recipient, 10).unwrap();
+        assert_eq!(
+            transfer_tokens(&mut ico_state, &buyer.info(), &recipient, 1),
+            Err(ICOError::FlipLocked.into())
+        );
+
+        set_time(PRE_SALE_TIME + 200);
+        transfer_tokens(&mut ico_state, &buyer.info(), &recipient, 40).unwrap();
+        assert_eq!(balance_of(&ico_state, &recipient), 50);
+        assert_eq!(balance_of(&ico_state, &buyer.key), 0);
+    }
+
+    #[test]
+    fn quoted_cost_matches_the_charged_cost() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.price_denominator = 3;
+        ico_state.rounding_mode = RoundingMode::Ceil;
+        ico_state.og_pre_sale_price = 8;
+        let mut ico = TestAccount::new(0, 0);
+        let mut og = TestAccount::buyer(0);
+        let mut regular = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &og.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &regular.key, 0, false).unwrap();
+        set_presale_tier(&mut ico_state, &og.key, TIER_OG).unwrap();
+
+        let mut charged = |ico_state: &mut ICOAccount, buyer: &mut TestAccount, amount| {
+            let time = UNIX_TIME.with(Cell::get).unwrap();
+            quote_cost(ico_state, Some(&buyer.key), amount).unwrap();
+            let quoted = u64_at(&return_data(), 0);
+            let before = buyer.lamports;
+            if time < ico_state.sale_start_time as i64 {
+                presale_purchase(ico_state, &mut ico, buyer, amount).unwrap();
+            } else {
+                sale_purchase(ico_state, &mut ico, buyer, amount).unwrap();
+            }
+            (quoted, before - buyer.lamports)
+        };
+
+        set_time(PRE_SALE_TIME);
+        assert_eq!(charged(&mut ico_state, &mut og, 10), (27, 27));
+        set_time(PRE_SALE_TIME);
+        assert_eq!(charged(&mut ico_state, &mut regular, 10), (34, 34));
+        set_time(SALE_TIME);
+        assert_eq!(charged(&mut ico_state, &mut regular, 10), (67, 67));
+
+        set_time(ENDED_TIME);
+        assert_eq!(
+            quote_cost(&ico_state, None, 10),
+            Err(ICOError::SaleNotActive.into())
+        );
+    }
+
+    #[test]
+    fn refunds_in_two_pages_resume_at_the_cursor() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyers = [
+            TestAccount::new(0, 0),
+            TestAccount::buyer(0),
+            TestAccount::buyer(0),
+            TestAccount::buyer(0),
+        ];
+        let unlisted = buyers[0].key;
+        ico_state.pre_sale_account.push(unlisted_entry(&unlisted));
+        for buyer in buyers[1..].iter_mut() {
+            sale_purchase(&mut ico_state, &mut ico, buyer, 10).unwrap();
+        }
+        cancel_ico(&mut ico_state, &admin.info()).unwrap();
+
+        let ico_info = ico.info();
+        let admin_info = admin.info();
+        let buyer_infos: Vec<_> = buyers.iter_mut().map(TestAccount::info).collect();
+        refund_page(
+            &mut ico_state,
+            &ico_info,
+            &admin_info,
+            &mut buyer_infos[..2].iter(),
+            0,
+            2,
+        )
+        .unwrap();
+        assert_eq!(ico_state.refund_cursor, 2);
+        assert_eq!(
+            refund_page(
+                &mut ico_state,
+                &ico_info,
+                &admin_info,
+                &mut buyer_infos[2..].iter(),
+                0,
+                2,
+            ),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        refund_page(
+            &mut ico_state,
+            &ico_info,
+            &admin_info,
+            &mut buyer_infos[2..].iter(),
+            2,
+            2,
+        )
+        .unwrap();
+        assert_eq!(ico_state.refund_cursor, 4);
+        for buyer_info in &buyer_infos[1..] {
+            assert_eq!(buyer_info.lamports(), BUYER_LAMPORTS);
+        }
+        assert_eq!(ico_info.lamports(), 0);
+
+        assert_eq!(
+            register_whitelisted(&mut ico_state, &Pubkey::new_unique(), 0, false),
+            Err(ICOError::Cancelled.into())
+        );
+        assert_eq!(
+            remove_presale_account(&mut ico_state, &admin_info, &unlisted),
+            Err(ICOError::Cancelled.into())
+        );
+    }
+
+    #[test]
+    fn presale_price_keeps_the_minimum_discount() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        assert_eq!(
+            update_config(&mut ico_state, &admin.info(), 12, 5_001),
+            Err(ICOError::InsufficientPresaleDiscount.into())
+        );
+        update_config(&mut ico_state, &admin.info(), 12, 5_000).unwrap();
+        assert_eq!(ico_state.min_presale_discount_bps, 5_000);
+
+        queue_action(
+            &mut ico_state,
+            &admin.info(),
+            TimelockAction::SetPreSalePrice(11),
+        )
+        .unwrap();
+        set_time(TIMELOCK_DELAY as i64);
+        assert_eq!(
+            execute_action(&mut ico_state, &admin.info()),
+            Err(ICOError::InsufficientPresaleDiscount.into())
+        );
+        ico_state.pre_sale_price = 10;
+        queue_action(
+            &mut ico_state,
+            &admin.info(),
+            TimelockAction::SetPreSalePrice(9),
+        )
+        .unwrap();
+        set_time(2 * TIMELOCK_DELAY as i64);
+        execute_action(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(ico_state.pre_sale_price, 9);
+    }
+
+    #[test]
+    fn spoofed_ico_accounts_are_rejected() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+        let mut spoofed = ico_account(&program_id, &mut test_state(&admin.key));
+        spoofed.key = Pubkey::new_unique();
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut recipient],
+            &mint_instruction(10, None),
+        )
+        .unwrap();
+        assert_eq!(
+            process(
+                &program_id,
+                &mut spoofed,
+                &mut [&mut admin, &mut recipient],
+                &mint_instruction(10, None),
+            ),
+            Err(ProgramError::InvalidSeeds)
+        );
+
+        let mut spoofed = empty_pda(&program_id, &admin.key, 2_048);
+        spoofed.key = Pubkey::new_unique();
+        assert_eq!(
+            process(
+                &program_id,
+                &mut spoofed,
+                &mut [&mut admin],
+                &init_instruction(&init_params()),
+            ),
+            Err(ProgramError::InvalidSeeds)
+        );
+        assert!(!read_state(&spoofed).is_initialized());
+    }
+
+    #[test]
+    fn required_account_len_matches_the_serialized_state() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let assert_len = |state: &ICOAccount| {
+            assert_eq!(
+                state.required_account_len(),
+                Ok(state.try_to_vec().unwrap().len() + ACCOUNT_SPACE_HEADROOM)
+            );
+        };
+        assert_len(&ICOAccount::default());
+        assert_len(&ico_state);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+        mint_vested_tokens(&mut ico_state, airdrop(&buyer.key, 100)).unwrap();
+        assert_len(&ico_state);
+
+        let program_id = Pubkey::new_unique();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        process(&program_id, &mut ico, &mut [], &[40]).unwrap();
+        assert_eq!(
+            u64_at(&return_data(), 0) as usize,
+            read_state(&ico).try_to_vec().unwrap().len() + ACCOUNT_SPACE_HEADROOM
+        );
+    }
+
+    #[test]
+    fn early_refund_within_the_cooling_off_window() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        ico_state.cooling_off_seconds = 20;
+        ico_state.early_refund_penalty_bps = 500;
+        let mut ico = TestAccount::new(0, 0);
+        let mut early = TestAccount::buyer(0);
+        let mut late = TestAccount::buyer(0);
+        sale_purchase(&mut ico_state, &mut ico, &mut early, 20).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut late, 20).unwrap();
+        let sale_supply = ico_state.sale_supply;
+
+        let ico_info = ico.info();
+        let early_info = early.info();
+        set_time(SALE_TIME + 20);
+        early_refund(&mut ico_state, &ico_info, &early_info).unwrap();
+        assert_eq!(early_info.lamports(), BUYER_LAMPORTS - 20);
+        assert_eq!(ico_info.lamports(), 420);
+        assert_eq!(ico_state.early_refund_penalties_collected, 20);
+        assert_eq!(ico_state.total_price_earned, 400);
+        assert_eq!(ico_state.contributed_lamports(&early.key), 0);
+        assert_eq!(balance_of(&ico_state, &early.key), 0);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY - 20);
+        assert_eq!(ico_state.sale_supply, sale_supply + 20);
+
+        set_time(SALE_TIME + 21);
+        assert_eq!(
+            early_refund(&mut ico_state, &ico_info, &late.info()),
+            Err(ICOError::CoolingOffExpired.into())
+        );
+    }
+
+    #[test]
+    fn balance_snapshots_are_tagged_with_the_slot() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        set_slot(10);
+        snapshot_balances(&mut ico_state, &admin.info()).unwrap();
+        let first = compute_balance_commitment(&ico_state);
+        mint_tokens(&mut ico_state, &Pubkey::new_unique(), 5).unwrap();
+        set_slot(20);
+        snapshot_balances(&mut ico_state, &admin.info()).unwrap();
+        let second = compute_balance_commitment(&ico_state);
+
+        assert_ne!(first, second);
+        assert_eq!(ico_state.balance_snapshots, vec![(10, first), (20, second)]);
+        assert!(logged("Balance snapshot taken at slot 20"));
+    }
+
+    #[test]
+    fn admin_instructions_run_without_the_clock() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut buyer = TestAccount::buyer(10);
+        let mut ico_state = test_state(&admin.key);
+        ico_state
+            .pre_sale_account
+            .push(unlisted_entry(&recipient.key));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        UNIX_TIME.with(|time| time.set(None));
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut recipient],
+            &mint_instruction(10, None),
+        )
+        .unwrap();
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut recipient, &mut admin],
+            &[4],
+        )
+        .unwrap();
+        let mut allowed_amounts = vec![29];
+        allowed_amounts.extend_from_slice(&10u64.to_le_bytes());
+        process(&program_id, &mut ico, &mut [&mut admin], &allowed_amounts).unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(balance_of(&ico_state, &recipient.key), 10);
+        assert!(ico_state.is_whitelisted(&recipient.key));
+        assert_eq!(ico_state.allowed_amounts, vec![10]);
+
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[3]),
+            Err(ICOError::ClockUnavailable.into())
+        );
+    }
+
+    #[test]
+    fn sale_purchase_balance_entries_follow_the_flag() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut auto_created = TestAccount::buyer(0);
+        let mut unregistered = TestAccount::buyer(0);
+
+        sale_purchase(&mut ico_state, &mut ico, &mut auto_created, 10).unwrap();
+        assert_eq!(balance_of(&ico_state, &auto_created.key), 10);
+
+        ico_state.require_balance_registration = true;
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut unregistered, 10),
+            Err(ICOError::BalanceNotRegistered.into())
+        );
+        sale_purchase(&mut ico_state, &mut ico, &mut auto_created, 10).unwrap();
+        assert_eq!(balance_of(&ico_state, &auto_created.key), 20);
+    }
+
+    #[test]
+    fn only_empty_balance_entries_count_against_the_registration_cap() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        for _ in 0..MAX_REGISTERED_BALANCES {
+            ico_state.balance_entry(&Pubkey::new_unique());
+        }
+        let mut holder = TestAccount::signer();
+        assert_eq!(
+            register_balance(&mut ico_state, &holder.info()),
+            Err(ICOError::EntryLimitReached.into())
+        );
+
+        let funded = ico_state
+            .balance
+            .iter()
+            .find(|(_, amount)| *amount == 0)
+            .unwrap()
+            .0;
+        mint_tokens(&mut ico_state, &funded, 1).unwrap();
+        register_balance(&mut ico_state, &holder.info()).unwrap();
+        assert!(ico_state.balance_index(&holder.key).is_ok());
+        register_balance(&mut ico_state, &holder.info()).unwrap();
+    }
+
+    #[test]
+    fn usage_counters_count_state_changes() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut first = TestAccount::signer();
+        let second = Pubkey::new_unique();
+        let mut buyer = TestAccount::buyer(10);
+        let mut ico_state = test_state(&admin.key);
+        ico_state.pre_sale_account.push(unlisted_entry(&first.key));
+        ico_state.pre_sale_account.push(unlisted_entry(&second));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        set_time(SALE_TIME);
+        for _ in 0..2 {
+            process(&program_id, &mut ico, &mut [&mut buyer], &[3]).unwrap();
+            process(&program_id, &mut ico, &mut [&mut first, &mut admin], &[4]).unwrap();
+        }
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut first],
+            &mint_instruction(5, None),
+        )
+        .unwrap();
+        let mut batch = vec![55];
+        batch.extend_from_slice(first.key.as_ref());
+        batch.extend_from_slice(second.as_ref());
+        process(&program_id, &mut ico, &mut [&mut admin], &batch).unwrap();
+
+        process(&program_id, &mut ico, &mut [], &[44]).unwrap();
+        let counters = return_data();
+        assert_eq!(
+            (0..3)
+                .map(|index| u64_at(&counters, index))
+                .collect::<Vec<_>>(),
+            vec![2, 1, 2]
+        );
+    }
+
+    /// Two sale buyers paying 400 and 600 lamports into a rent-exempt ICO
+    /// account, with a soft cap the sale misses.
+    fn missed_soft_cap() -> (ICOAccount, TestAccount, TestAccount, TestAccount) {
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.soft_cap = 10_000;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let mut first = TestAccount::buyer(0);
+        let mut second = TestAccount::buyer(0);
+        sale_purchase(&mut ico_state, &mut ico, &mut first, 20).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut second, 30).unwrap();
+        set_time(ENDED_TIME);
+        (ico_state, ico, first, second)
+    }
+
+    #[test]
+    fn soft_cap_refunds_return_full_contributions_once() {
+        setup();
+        let (mut ico_state, mut ico, mut first, mut second) = missed_soft_cap();
+        let rent_minimum = ico.lamports - 1_000;
+
+        let ico_info = ico.info();
+        let first_info = first.info();
+        let second_info = second.info();
+        claim_soft_cap_refund(&mut ico_state, &ico_info, &first_info).unwrap();
+        claim_soft_cap_refund(&mut ico_state, &ico_info, &second_info).unwrap();
+        assert_eq!(first_info.lamports(), BUYER_LAMPORTS);
+        assert_eq!(second_info.lamports(), BUYER_LAMPORTS);
+        assert_eq!(ico_info.lamports(), rent_minimum);
+        assert_eq!(
+            ico_state.soft_cap_refunds,
+            vec![(*first_info.key, 400), (*second_info.key, 600)]
+        );
+        assert_eq!(
+            claim_soft_cap_refund(&mut ico_state, &ico_info, &first_info),
+            Err(ICOError::AlreadyRefunded.into())
+        );
+    }
+
+    #[test]
+    fn soft_cap_refunds_are_prorated_on_a_shortfall() {
+        setup();
+        let (mut ico_state, mut ico, mut first, mut second) = missed_soft_cap();
+        ico.lamports -= 500;
+
+        let ico_info = ico.info();
+        claim_soft_cap_refund(&mut ico_state, &ico_info, &first.info()).unwrap();
+        claim_soft_cap_refund(&mut ico_state, &ico_info, &second.info()).unwrap();
+        assert_eq!(
+            ico_state.soft_cap_refunds,
+            vec![(first.key, 200), (second.key, 300)]
+        );
+        assert!(logged(
+            "Prorating refund: 500 lamports available for 1000 owed"
+        ));
+    }
+
+    #[test]
+    fn cancellation_refund_blocks_a_second_soft_cap_refund() {
+        setup();
+        let (mut ico_state, mut ico, mut first, _) = missed_soft_cap();
+        ico_state.cancelled = true;
+
+        let ico_info = ico.info();
+        let first_info = first.info();
+        claim_refund(&mut ico_state, &ico_info, &first_info, &first_info).unwrap();
+        assert_eq!(
+            claim_soft_cap_refund(&mut ico_state, &ico_info, &first_info),
+            Err(ICOError::AlreadyRefunded.into())
+        );
+        assert_eq!(first_info.lamports(), BUYER_LAMPORTS);
+    }
+
+    #[test]
+    fn refund_returns_what_the_buyer_still_holds() {
+        setup();
+        let (mut ico_state, mut ico, mut first, _) = missed_soft_cap();
+        let admin = ico_state.admin;
+        let sale_supply = ico_state.sale_supply;
+        let recipient = Pubkey::new_unique();
+        ico_state.transfers_enabled = true;
+        transfer_tokens(&mut ico_state, &first.info(), &recipient, 5).unwrap();
+
+        claim_soft_cap_refund(&mut ico_state, &ico.info(), &first.info()).unwrap();
+        assert_eq!(first.lamports, BUYER_LAMPORTS);
+        assert_eq!(balance_of(&ico_state, &first.key), 0);
+        assert_eq!(balance_of(&ico_state, &recipient), 5);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY - 35);
+        assert_eq!(ico_state.sale_supply, sale_supply + 15);
+        assert!(logged("Buyer no longer holds 5 of the 20 tokens to return"));
+    }
+
+    fn reveal_purchase(
+        ico_state: &mut ICOAccount,
+        ico: &mut TestAccount,
+        buyer: &mut TestAccount,
+        amount: u64,
+        nonce: [u8; 32],
+    ) -> ProgramResult {
+        buyer.set_amount(amount);
+        let ico_info = ico.info();
+        pre_sale(
+            ico_state,
+            &ico_info,
+            &mut [buyer.info()].iter(),
+            None,
+            Some(nonce),
+        )
+    }
+
+    #[test]
+    fn presale_purchases_reveal_their_commitment() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.commit_deadline = 40;
+        let mut ico = TestAccount::new(0, 0);
+        let mut honest = TestAccount::buyer(0);
+        let mut cheater = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &honest.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &cheater.key, 0, false).unwrap();
+        let nonce = [9; 32];
+        let commitment = hashv(&[&30u64.to_le_bytes(), &nonce]).to_bytes();
+
+        set_time(10);
+        commit_purchase(&mut ico_state, &honest.info(), commitment).unwrap();
+        commit_purchase(&mut ico_state, &cheater.info(), commitment).unwrap();
+        assert_eq!(
+            reveal_purchase(&mut ico_state, &mut ico, &mut honest, 30, nonce),
+            Err(ICOError::CommitWindowOpen.into())
+        );
+
+        set_time(40);
+        assert_eq!(
+            commit_purchase(&mut ico_state, &honest.info(), commitment),
+            Err(ICOError::CommitWindowClosed.into())
+        );
+        reveal_purchase(&mut ico_state, &mut ico, &mut honest, 30, nonce).unwrap();
+        assert_eq!(balance_of(&ico_state, &honest.key), 30);
+        assert_eq!(
+            reveal_purchase(&mut ico_state, &mut ico, &mut honest, 30, nonce),
+            Err(ICOError::InvalidReveal.into())
+        );
+        assert_eq!(
+            reveal_purchase(&mut ico_state, &mut ico, &mut cheater, 60, nonce),
+            Err(ICOError::InvalidReveal.into())
+        );
+        assert_eq!(balance_of(&ico_state, &cheater.key), 0);
+    }
+
+    #[test]
+    fn full_whitelist_rejects_or_evicts_the_oldest_idle_entry() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.max_presale_entries = 2;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let idle = Pubkey::new_unique();
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &idle, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 10).unwrap();
+
+        let newcomer = Pubkey::new_unique();
+        assert_eq!(
+            register_whitelisted(&mut ico_state, &newcomer, 0, false),
+            Err(ICOError::WhitelistFull.into())
+        );
+
+        ico_state.eviction_policy = EvictionPolicy::EvictOldest;
+        register_whitelisted(&mut ico_state, &newcomer, 0, false).unwrap();
+        let addresses: Vec<Pubkey> = ico_state
+            .pre_sale_account
+            .iter()
+            .map(|entry| entry.address)
+            .collect();
+        assert_eq!(addresses, vec![buyer.key, newcomer]);
+        assert!(logged(&format!("Evicted presale entry {}", idle)));
+
+        ico_state.pre_sale_account[1].token_amount = 1;
+        assert_eq!(
+            register_whitelisted(&mut ico_state, &Pubkey::new_unique(), 0, false),
+            Err(ICOError::WhitelistFull.into())
+        );
+    }
+
+    #[test]
+    fn dry_run_reports_the_first_failing_check() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico = ico_account(&program_id, &mut test_state(&Pubkey::new_unique()));
+        let mut cases: Vec<(InitParams, u8)> = vec![(init_params(), 0)];
+        let mut params = init_params();
+        params.total_supply = 0;
+        cases.push((params, InitCheck::ZeroSupply as u8));
+        let mut params = init_params();
+        params.sale_price = 0;
+        params.sale_end_time = 0;
+        cases.push((params, InitCheck::ZeroPrice as u8));
+        let mut params = init_params();
+        params.sale_supply = ADMIN_SUPPLY;
+        cases.push((params, InitCheck::PhaseSupplyExceedsTotal as u8));
+        let mut params = init_params();
+        params.sale_end_time = params.sale_start_time;
+        cases.push((params, InitCheck::InvalidSaleWindow as u8));
+
+        for (params, code) in cases {
+            let mut instruction_data = init_instruction(&params);
+            instruction_data[0] = 47;
+            process(&program_id, &mut ico, &mut [], &instruction_data).unwrap();
+            assert_eq!(return_data(), vec![code], "{:?}", params);
+        }
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [], &[47, 1, 2]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn transfers_stay_disabled_until_the_admin_enables_them() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut buyer = TestAccount::buyer(10);
+        let mut recipient = TestAccount::new(0, 0);
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+        let mut transfer = vec![48];
+        transfer.extend_from_slice(&4u64.to_le_bytes());
+
+        set_time(SALE_TIME);
+        process(&program_id, &mut ico, &mut [&mut buyer], &[3]).unwrap();
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut buyer, &mut recipient],
+                &transfer
+            ),
+            Err(ICOError::TransfersDisabled.into())
+        );
+
+        process(&program_id, &mut ico, &mut [&mut admin], &[49, 1]).unwrap();
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut buyer, &mut recipient],
+            &transfer,
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(balance_of(&ico_state, &buyer.key), 6);
+        assert_eq!(balance_of(&ico_state, &recipient.key), 4);
+    }
+
+    #[test]
+    fn underfunded_presale_purchase_is_rejected_cleanly() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut buyer = TestAccount::buyer(40);
+        buyer.lamports = 399;
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let ico_lamports = ico.lamports;
+
+        set_time(PRE_SALE_TIME);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[2]),
+            Err(ProgramError::InsufficientFunds)
+        );
+        assert_eq!(buyer.lamports, 399);
+        assert_eq!(ico.lamports, ico_lamports);
+    }
+
+    /// `(version, kind, fields)` of a parsed purchase event.
+    type PurchaseEvent = (u8, String, Vec<(String, String)>);
+
+    /// Parses the purchase events in the captured logs by their documented
+    /// grammar.
+    fn purchase_events() -> Vec<PurchaseEvent> {
+        LOGS.with(|logs| {
+            logs.borrow()
+                .iter()
+                .filter_map(|log| log.strip_prefix("ICO:v"))
+                .map(|event| {
+                    let mut parts = event.splitn(3, ':');
+                    let version = parts.next().unwrap().parse().unwrap();
+                    let kind = parts.next().unwrap().to_string();
+                    let fields = parts
+                        .next()
+                        .unwrap()
+                        .split(';')
+                        .map(|field| {
+                            let (key, value) = field.split_once('=').unwrap();
+                            (key.to_string(), value.to_string())
+                        })
+                        .collect();
+                    (version, kind, fields)
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn purchase_events_parse_back_into_fields() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.early_bird_bonus_bps = 1_000;
+        ico_state.early_bird_deadline = PRE_SALE_TIME as u64;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 5).unwrap();
+
+        let fields = |amount: u64, bonus: u64, cost: u64| {
+            vec![
+                ("buyer".to_string(), buyer.key.to_string()),
+                ("payer".to_string(), buyer.key.to_string()),
+                ("amount".to_string(), amount.to_string()),
+                ("bonus".to_string(), bonus.to_string()),
+                ("cost".to_string(), cost.to_string()),
+            ]
+        };
+        assert_eq!(
+            purchase_events(),
+            vec![
+                (EVENT_VERSION, "PRESALE".to_string(), fields(40, 4, 400)),
+                (EVENT_VERSION, "SALE".to_string(), fields(5, 0, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn admin_rescues_a_mistaken_transfer() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.transfers_enabled = true;
+        let mut sender = TestAccount::signer();
+        let stranger = Pubkey::new_unique();
+        mint_tokens(&mut ico_state, &sender.key, 50).unwrap();
+        transfer_tokens(&mut ico_state, &sender.info(), &admin.key, 30).unwrap();
+        assert_eq!(ico_state.transfers_to_admin, vec![(sender.key, 30)]);
+
+        assert_eq!(
+            rescue_admin_transfer(&mut ico_state, &sender.info(), &stranger, 30),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            rescue_admin_transfer(&mut ico_state, &admin.info(), &stranger, 10),
+            Err(ICOError::NothingToRescue.into())
+        );
+        assert_eq!(
+            rescue_admin_transfer(&mut ico_state, &admin.info(), &sender.key, 31),
+            Err(ICOError::NothingToRescue.into())
+        );
+
+        rescue_admin_transfer(&mut ico_state, &admin.info(), &sender.key, 10).unwrap();
+        assert_eq!(ico_state.transfers_to_admin, vec![(sender.key, 20)]);
+        rescue_admin_transfer(&mut ico_state, &admin.info(), &sender.key, 20).unwrap();
+        assert!(ico_state.transfers_to_admin.is_empty());
+        assert_eq!(balance_of(&ico_state, &sender.key), 50);
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY);
+    }
+
+    #[test]
+    fn sold_out_and_paused_purchases_exit_before_any_scan() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        for _ in 0..10_000 {
+            ico_state.balance.push((Pubkey::new_unique(), 1));
+        }
+        let mut ico = TestAccount::new(0, 0);
+        // Without data the purchase amount can't even be read.
+        let mut buyer = TestAccount::new(BUYER_LAMPORTS, 0);
+        set_time(SALE_TIME);
+
+        ico_state.sold_out = true;
+        assert_eq!(
+            sale(&mut ico_state, &ico.info(), &mut [buyer.info()].iter()),
+            Err(ICOError::SoldOut.into())
+        );
+        ico_state.paused = true;
+        assert_eq!(
+            sale(&mut ico_state, &ico.info(), &mut [buyer.info()].iter()),
+            Err(ICOError::Paused.into())
+        );
+        ico_state.sold_out = false;
+        ico_state.paused = false;
+        assert_eq!(
+            sale(&mut ico_state, &ico.info(), &mut [buyer.info()].iter()),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn holdings_breakdown_after_mixed_activity() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.transfers_enabled = true;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 40).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 25).unwrap();
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 5).unwrap();
+        mint_tokens(&mut ico_state, &buyer.key, 7).unwrap();
+        transfer_tokens(&mut ico_state, &buyer.info(), &Pubkey::new_unique(), 12).unwrap();
+
+        holdings_breakdown(&ico_state, &buyer.key).unwrap();
+        let data = return_data();
+        assert_eq!(
+            (0..3).map(|index| u64_at(&data, index)).collect::<Vec<_>>(),
+            vec![40, 30, 65]
+        );
+    }
+
+    #[test]
+    fn presale_tiers_get_their_price_and_allocation() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.og_pre_sale_price = 8;
+        ico_state.og_allocation = 50;
+        let mut ico = TestAccount::new(0, 0);
+        let mut og = TestAccount::buyer(0);
+        let mut regular = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &og.key, 0, false).unwrap();
+        register_whitelisted(&mut ico_state, &regular.key, 20, false).unwrap();
+        set_presale_tier(&mut ico_state, &og.key, TIER_OG).unwrap();
+
+        presale_purchase(&mut ico_state, &mut ico, &mut og, 50).unwrap();
+        assert_eq!(og.lamports, BUYER_LAMPORTS - 400);
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut og, 1),
+            Err(ProgramError::InvalidInstructionData)
+        );
+
+        presale_purchase(&mut ico_state, &mut ico, &mut regular, 20).unwrap();
+        assert_eq!(regular.lamports, BUYER_LAMPORTS - 200);
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut regular, 1),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert_eq!(
+            set_presale_tier(&mut ico_state, &regular.key, 2),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn short_buyer_account_data_errors_instead_of_panicking() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut buyer = TestAccount::new(BUYER_LAMPORTS, 4);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        set_time(PRE_SALE_TIME);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[2]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        set_time(SALE_TIME);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[3]),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert!(logged(
+            "Buyer account data is too small to hold the purchase amount"
+        ));
+    }
+
+    #[test]
+    fn purchases_carry_the_terms_hash() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut buyer = TestAccount::buyer(10);
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+        let terms_hash = [5; 32];
+        let mut set_terms = vec![53];
+        set_terms.extend_from_slice(&terms_hash);
+        process(&program_id, &mut ico, &mut [&mut admin], &set_terms).unwrap();
+        process(&program_id, &mut ico, &mut [], &[54]).unwrap();
+        assert_eq!(return_data(), terms_hash.to_vec());
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut admin], &set_terms),
+            Err(ICOError::TermsAlreadySet.into())
+        );
+
+        set_time(SALE_TIME);
+        let mut purchase = vec![3];
+        purchase.extend_from_slice(&[6; 32]);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &purchase),
+            Err(ICOError::TermsMismatch.into())
+        );
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[3]),
+            Err(ICOError::TermsMismatch.into())
+        );
+        purchase[1..].copy_from_slice(&terms_hash);
+        process(&program_id, &mut ico, &mut [&mut buyer], &purchase).unwrap();
+        assert_eq!(balance_of(&read_state(&ico), &buyer.key), 10);
+    }
+
+    #[test]
+    fn batch_whitelist_reports_a_bitmap() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let addresses: Vec<Pubkey> = (0..10).map(|_| Pubkey::new_unique()).collect();
+        for index in [0, 2, 3, 9] {
+            ico_state
+                .pre_sale_account
+                .push(unlisted_entry(&addresses[index]));
+        }
+        register_whitelisted(&mut ico_state, &addresses[3], 0, true).unwrap();
+
+        batch_whitelist(&mut ico_state, &addresses).unwrap();
+        assert_eq!(return_data(), vec![0b0000_1101, 0b0000_0010]);
+        assert_eq!(ico_state.total_whitelists, 3);
+        for index in [0, 2, 3, 9] {
+            assert!(ico_state.is_whitelisted(&addresses[index]));
+        }
+    }
+
+    #[test]
+    fn price_bounds_reject_free_and_overflowing_purchases() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        assert_eq!(
+            update_config(&mut ico_state, &admin.info(), 0, u64::MAX / 10),
+            Err(ICOError::InvalidPriceBounds.into())
+        );
+        assert!(logged("would overflow"));
+        update_config(&mut ico_state, &admin.info(), 0, u64::MAX / 20).unwrap();
+
+        ico_state.rounding_mode = RoundingMode::Ceil;
+        queue_action(
+            &mut ico_state,
+            &admin.info(),
+            TimelockAction::SetPriceDenominator(100),
+        )
+        .unwrap();
+        set_time(TIMELOCK_DELAY as i64);
+        execute_action(&mut ico_state, &admin.info()).unwrap();
+
+        assert_eq!(
+            update_config(&mut ico_state, &admin.info(), 1, 0),
+            Err(ICOError::InvalidPriceBounds.into())
+        );
+        assert!(logged("A purchase of 1 tokens at price 10 would be free"));
+        set_allowed_amounts(&mut ico_state, &admin.info(), vec![10, 50]).unwrap();
+        ico_state.rounding_mode = RoundingMode::Floor;
+        ico_state.check_price_bounds().unwrap();
+    }
+
+    #[test]
+    fn funds_accounting_after_a_purchase_and_withdrawal() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut treasury = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let mut ico_state = test_state(&admin.key);
+        ico_state.treasury = treasury.key;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let funds = |ico_state: &ICOAccount, ico: &mut TestAccount| {
+            funds_accounting(ico_state, &ico.info()).unwrap();
+            let data = return_data();
+            (0..3).map(|index| u64_at(&data, index)).collect::<Vec<_>>()
+        };
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 50).unwrap();
+        assert_eq!(funds(&ico_state, &mut ico), vec![1_000, 0, 1_000]);
+
+        set_time(ENDED_TIME);
+        withdraw_proceeds(
+            &mut ico_state,
+            &ico.info(),
+            &admin.info(),
+            &mut [treasury.info()].iter(),
+        )
+        .unwrap();
+        assert_eq!(treasury.lamports, 1_000);
+        ico.lamports += 250;
+        assert_eq!(funds(&ico_state, &mut ico), vec![1_000, 1_000, 250]);
+    }
+
+    #[test]
+    fn preview_reservations_convert_into_a_real_purchase() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.whitelist_requirement = WhitelistRequirement::None;
+        ico_state.commit_deadline = 40;
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let mut unlisted = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 50, false).unwrap();
+        let nonce = [3; 32];
+        set_time(10);
+        commit_purchase(
+            &mut ico_state,
+            &buyer.info(),
+            hashv(&[&30u64.to_le_bytes(), &nonce]).to_bytes(),
+        )
+        .unwrap();
+
+        ico_state.preview_mode = true;
+        set_time(PRE_SALE_TIME);
+        assert_eq!(
+            reveal_purchase(&mut ico_state, &mut ico, &mut unlisted, 30, nonce),
+            Err(ICOError::NotWhitelisted.into())
+        );
+        reveal_purchase(&mut ico_state, &mut ico, &mut buyer, 30, [0; 32]).unwrap();
+        assert_eq!(ico_state.preview_reservations, vec![(buyer.key, 30)]);
+        assert_eq!(ico_state.purchase_commitments.len(), 1);
+        assert_eq!(
+            reveal_purchase(&mut ico_state, &mut ico, &mut buyer, 21, [0; 32]),
+            Err(ProgramError::InvalidInstructionData)
+        );
+        assert_eq!(buyer.lamports, BUYER_LAMPORTS);
+        assert_eq!(ico_state.pre_sale_supply, 3_000);
+        assert_eq!(balance_of(&ico_state, &buyer.key), 0);
+
+        ico_state.preview_mode = false;
+        reveal_purchase(&mut ico_state, &mut ico, &mut buyer, 30, nonce).unwrap();
+        assert!(ico_state.preview_reservations.is_empty());
+        assert!(ico_state.purchase_commitments.is_empty());
+        assert_eq!(buyer.lamports, BUYER_LAMPORTS - 300);
+        assert_eq!(ico_state.pre_sale_supply, 2_970);
+        assert_eq!(balance_of(&ico_state, &buyer.key), 30);
+    }
+
+    #[test]
+    fn purchase_reaching_the_hard_cap_closes_the_sale() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.hard_cap = 1_000;
+        let mut ico = TestAccount::new(0, 0);
+        let mut first = TestAccount::buyer(0);
+        let mut second = TestAccount::buyer(0);
+
+        sale_purchase(&mut ico_state, &mut ico, &mut first, 49).unwrap();
+        assert!(!ico_state.sold_out);
+        sale_purchase(&mut ico_state, &mut ico, &mut first, 1).unwrap();
+        assert!(ico_state.sold_out);
+        assert!(logged("Hard cap reached, closing purchases"));
+        assert_eq!(
+            sale_purchase(&mut ico_state, &mut ico, &mut second, 1),
+            Err(ICOError::SoldOut.into())
+        );
+    }
+
+    #[test]
+    fn treasury_updates_directly_or_in_two_steps() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let direct = Pubkey::new_unique();
+        let mut proposed = TestAccount::signer();
+        let mut impostor = TestAccount::signer();
+
+        assert_eq!(
+            set_treasury(&mut ico_state, &admin.info(), &Pubkey::default(), false),
+            Err(ICOError::InvalidTreasury.into())
+        );
+        set_treasury(&mut ico_state, &admin.info(), &direct, false).unwrap();
+        assert_eq!(ico_state.treasury, direct);
+
+        set_treasury(&mut ico_state, &admin.info(), &proposed.key, true).unwrap();
+        assert_eq!(ico_state.treasury, direct);
+        assert_eq!(
+            accept_treasury(&mut ico_state, &impostor.info()),
+            Err(ICOError::InvalidTreasury.into())
+        );
+        proposed.is_signer = false;
+        assert_eq!(
+            accept_treasury(&mut ico_state, &proposed.info()),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        proposed.is_signer = true;
+        accept_treasury(&mut ico_state, &proposed.info()).unwrap();
+        assert_eq!(ico_state.treasury, proposed.key);
+        assert_eq!(ico_state.pending_treasury, Pubkey::default());
+    }
+
+    #[test]
+    fn exported_config_decodes_into_the_versioned_struct() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        ico_state.hard_cap = 5_000;
+        ico_state.whitelist_mode = WhitelistMode::Merkle;
+        ico_state.flip_lock_seconds = 60;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        process(&program_id, &mut ico, &mut [], &[59]).unwrap();
+        let data = return_data();
+        let config = ICOConfigV2::try_from_slice(&data).unwrap();
+        assert_eq!(config.config_version, CONFIG_VERSION);
+        assert_eq!(config.account_version, CURRENT_VERSION);
+        assert_eq!(config.admin, admin);
+        assert_eq!(config.total_supply, ADMIN_SUPPLY);
+        assert_eq!((config.pre_sale_price, config.sale_price), (10, 20));
+        assert_eq!(config.hard_cap, 5_000);
+        assert_eq!(config.whitelist_mode, WhitelistMode::Merkle);
+        assert_eq!(config.flip_lock_seconds, 60);
+
+        // Version 2 only appended fields, so the version 1 layout is a prefix.
+        let v1 = ICOConfigV1::deserialize(&mut &data[..]).unwrap();
+        assert_eq!(v1.hard_cap, 5_000);
+        assert_eq!(v1.sale_end_time, 200);
+    }
+
+    /// Ed25519 program data carrying one inline signature over
+    /// `buyer || allocation`. The signature bytes stay zeroed: checking them is
+    /// the ed25519 program's job, the ICO only reads the key and message.
+    fn signature_instruction(authority: &Pubkey, buyer: &Pubkey, allocation: u64) -> Vec<u8> {
+        let (public_key_offset, signature_offset, message_offset) = (16u16, 48u16, 112u16);
+        let mut data = vec![1, 0];
+        for offset in [
+            signature_offset,
+            u16::MAX,
+            public_key_offset,
+            u16::MAX,
+            message_offset,
+            40,
+            u16::MAX,
+        ] {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(authority.as_ref());
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(buyer.as_ref());
+        data.extend_from_slice(&allocation.to_le_bytes());
+        data
+    }
+
+    /// Instructions sysvar for a transaction of `previous` followed by the
+    /// purchase, with the purchase as the current instruction.
+    fn instructions_sysvar(previous: Instruction) -> TestAccount {
+        let purchase = Instruction::new_with_bytes(Pubkey::new_unique(), &[2], vec![]);
+        let mut data = Message::new(&[previous, purchase], None).serialize_instructions(false);
+        data.resize(data.len() + 2, 0);
+        instructions::store_current_index(&mut data, 1);
+        TestAccount {
+            key: instructions::id(),
+            owner: solana_program::sysvar::id(),
+            lamports: 0,
+            data,
+            is_signer: false,
+        }
+    }
+
+    fn signed_presale(
+        ico_state: &mut ICOAccount,
+        buyer: &mut TestAccount,
+        sysvar: &mut TestAccount,
+    ) -> ProgramResult {
+        set_time(PRE_SALE_TIME);
+        let mut ico = TestAccount::new(0, 0);
+        let ico_info = ico.info();
+        let buyer_info = buyer.info();
+        let accounts = [buyer_info.clone(), buyer_info, sysvar.info()];
+        pre_sale(ico_state, &ico_info, &mut accounts.iter(), None, None)
+    }
+
+    #[test]
+    fn signature_whitelist_accepts_the_authority_and_rejects_forgeries() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let authority = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin.key);
+        set_whitelist_authority(&mut ico_state, &admin.info(), authority).unwrap();
+        assert_eq!(ico_state.whitelist_mode, WhitelistMode::Signature);
+        let mut buyer = TestAccount::buyer(40);
+        let signed = |signer: &Pubkey, covered: &Pubkey| {
+            let data = signature_instruction(signer, covered, 50);
+            instructions_sysvar(Instruction::new_with_bytes(
+                ed25519_program::id(),
+                &data,
+                vec![],
+            ))
+        };
+
+        // Signed by another key, or by the authority for another buyer.
+        for mut sysvar in [
+            signed(&Pubkey::new_unique(), &buyer.key),
+            signed(&authority, &Pubkey::new_unique()),
+        ] {
+            assert_eq!(
+                signed_presale(&mut ico_state, &mut buyer, &mut sysvar),
+                Err(ICOError::NotWhitelisted.into())
+            );
+        }
+        // The signature must come from the ed25519 program.
+        let data = signature_instruction(&authority, &buyer.key, 50);
+        let mut sysvar = instructions_sysvar(Instruction::new_with_bytes(
+            Pubkey::new_unique(),
+            &data,
+            vec![],
+        ));
+        assert_eq!(
+            signed_presale(&mut ico_state, &mut buyer, &mut sysvar),
+            Err(ICOError::NotWhitelisted.into())
+        );
+        assert!(ico_state.pre_sale_account.is_empty());
+
+        let mut sysvar = signed(&authority, &buyer.key);
+        signed_presale(&mut ico_state, &mut buyer, &mut sysvar).unwrap();
+        let entry = &ico_state.pre_sale_account[0];
+        assert_eq!(entry.address, buyer.key);
+        assert!(entry.whitelist_account);
+        assert_eq!((entry.allocation, entry.token_amount), (50, 40));
+    }
+
+    #[test]
+    fn allocation_adjusts_freely_until_it_falls_below_purchases() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &buyer.key, 100, false).unwrap();
+        let allocation = |ico_state: &ICOAccount| ico_state.pre_sale_account[0].allocation;
+
+        set_allocation(&mut ico_state, &admin.info(), &buyer.key, 20).unwrap();
+        assert_eq!(allocation(&ico_state), 20);
+        set_allocation(&mut ico_state, &admin.info(), &buyer.key, 80).unwrap();
+        assert_eq!(allocation(&ico_state), 80);
+
+        presale_purchase(&mut ico_state, &mut ico, &mut buyer, 60).unwrap();
+        set_allocation(&mut ico_state, &admin.info(), &buyer.key, 120).unwrap();
+        assert_eq!(allocation(&ico_state), 120);
+        set_allocation(&mut ico_state, &admin.info(), &buyer.key, 60).unwrap();
+        assert_eq!(allocation(&ico_state), 60);
+        assert_eq!(
+            set_allocation(&mut ico_state, &admin.info(), &buyer.key, 59),
+            Err(ICOError::AllocationBelowPurchased.into())
+        );
+        assert_eq!(allocation(&ico_state), 60);
+        assert_eq!(
+            set_allocation(&mut ico_state, &admin.info(), &Pubkey::new_unique(), 10),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn reentrant_call_is_rejected_while_the_account_is_locked() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut target = TestAccount::signer();
+        // An outer instruction persists the lock before its CPIs, so a call
+        // back into the program finds the account locked.
+        let mut ico_state = test_state(&admin.key);
+        ico_state.pre_sale_account.push(unlisted_entry(&target.key));
+        ico_state.locked = true;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut target, &mut admin], &[4]),
+            Err(ICOError::Reentrancy.into())
+        );
+        assert!(logged("Reentrant call rejected"));
+        assert!(!read_state(&ico).is_whitelisted(&target.key));
+
+        // Once the outer instruction finishes the account is unlocked again.
+        let mut ico_state = test_state(&admin.key);
+        ico_state.pre_sale_account.push(unlisted_entry(&target.key));
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        process(&program_id, &mut ico, &mut [&mut target, &mut admin], &[4]).unwrap();
+        let ico_state = read_state(&ico);
+        assert!(!ico_state.locked);
+        assert!(ico_state.is_whitelisted(&target.key));
+    }
+
+    #[test]
+    fn view_envelopes_decode_by_kind() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut participant = TestAccount::signer();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        register_whitelisted(&mut ico_state, &participant.key, 70, false).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        process(&program_id, &mut ico, &mut [&mut participant], &[5]).unwrap();
+        let envelope = ReturnEnvelope::try_from_slice(&return_data()).unwrap();
+        assert_eq!(envelope.kind, RETURN_REMAINING_ALLOCATION);
+        assert_eq!(envelope.payload, 70u64.to_le_bytes().to_vec());
+
+        process(&program_id, &mut ico, &mut [], &[9]).unwrap();
+        let envelope = ReturnEnvelope::try_from_slice(&return_data()).unwrap();
+        assert_eq!(envelope.kind, RETURN_SUPPLY_INFO);
+        assert_eq!(envelope.payload.len(), 16);
+        assert_eq!(u64_at(&envelope.payload, 0), ADMIN_SUPPLY);
+        assert_eq!(u64_at(&envelope.payload, 1), 0);
+    }
+
+    #[test]
+    fn underfunded_purchase_is_scaled_only_within_tolerance() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let reserve = Rent::default().minimum_balance(8);
+        let mut buyer = TestAccount::buyer(100);
+        set_time(SALE_TIME);
+        let ico_with = |tolerance: u64, allowed_amounts: Vec<u64>| {
+            let mut ico_state = test_state(&Pubkey::new_unique());
+            ico_state.lamport_tolerance = tolerance;
+            ico_state.allowed_amounts = allowed_amounts;
+            ico_account(&program_id, &mut ico_state)
+        };
+
+        // 100 tokens cost 2_000 lamports and the buyer can spend 1_990.
+        for (tolerance, allowed_amounts) in [(0, vec![]), (5, vec![]), (20, vec![100])] {
+            let mut ico = ico_with(tolerance, allowed_amounts);
+            buyer.lamports = reserve + 1_990;
+            assert_eq!(
+                process(&program_id, &mut ico, &mut [&mut buyer], &[3]),
+                Err(ICOError::BelowRentExemption.into())
+            );
+            assert_eq!(buyer.lamports, reserve + 1_990);
+        }
+
+        let mut ico = ico_with(20, vec![]);
+        let ico_lamports = ico.lamports;
+        process(&program_id, &mut ico, &mut [&mut buyer], &[3]).unwrap();
+        assert!(logged(
+            "Underfunded by 10 lamports, buying 99 of 100 tokens"
+        ));
+        assert_eq!(balance_of(&read_state(&ico), &buyer.key), 99);
+        assert_eq!(buyer.lamports, reserve + 10);
+        assert_eq!(ico.lamports, ico_lamports + 1_980);
+    }
+
+    #[test]
+    fn force_completing_a_partially_vested_schedule() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut beneficiary = TestAccount::signer();
+        let mut recipient = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut schedule = airdrop(&beneficiary.key, 1_000);
+        schedule.end_time = 1_000;
+        mint_vested_tokens(&mut ico_state, schedule).unwrap();
+        set_time(ENDED_TIME);
+        claim_airdrop(&mut ico_state, &beneficiary.info()).unwrap();
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 250);
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        set_time(SALE_TIME);
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut admin, &mut beneficiary, &mut recipient],
+                &[62]
+            ),
+            Err(ICOError::SaleNotActive.into())
+        );
+        set_time(ENDED_TIME);
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut recipient, &mut beneficiary, &mut admin],
+                &[62]
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut beneficiary, &mut recipient],
+            &[62],
+        )
+        .unwrap();
+        let mut ico_state = read_state(&ico);
+        assert_eq!(balance_of(&ico_state, &recipient.key), 750);
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 250);
+        assert_eq!(ico_state.airdrop_vesting[0].claimed_amount, 1_000);
+        assert_eq!(
+            claim_airdrop(&mut ico_state, &beneficiary.info()),
+            Err(ICOError::NothingToClaim.into())
+        );
+        assert_eq!(
+            force_complete_vesting(
+                &mut ico_state,
+                &admin.info(),
+                &beneficiary.key,
+                &recipient.key
+            ),
+            Err(ICOError::NothingToClaim.into())
+        );
+    }
+
+    #[test]
+    fn presale_requires_a_minimum_account_age() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.min_account_age = 30;
+        let mut ico = TestAccount::new(0, 0);
+        let mut aged = TestAccount::buyer(0);
+        let mut fresh = TestAccount::buyer(0);
+        let mut unseen = TestAccount::buyer(0);
+        for buyer in [&aged, &fresh, &unseen] {
+            register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        }
+        set_time(PRE_SALE_TIME - 30);
+        register_balance(&mut ico_state, &aged.info()).unwrap();
+        set_time(PRE_SALE_TIME - 29);
+        register_balance(&mut ico_state, &fresh.info()).unwrap();
+
+        for buyer in [&mut fresh, &mut unseen] {
+            assert_eq!(
+                presale_purchase(&mut ico_state, &mut ico, buyer, 10),
+                Err(ICOError::AccountTooNew.into())
+            );
+        }
+        presale_purchase(&mut ico_state, &mut ico, &mut aged, 10).unwrap();
+        assert_eq!(balance_of(&ico_state, &aged.key), 10);
+    }
+
+    #[test]
+    fn whitelist_pages_skip_unlisted_entries() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let listed: Vec<Pubkey> = (0..5).map(|_| Pubkey::new_unique()).collect();
+        for (index, address) in listed.iter().enumerate() {
+            register_whitelisted(&mut ico_state, address, 0, false).unwrap();
+            if index == 2 {
+                ico_state
+                    .pre_sale_account
+                    .push(unlisted_entry(&Pubkey::new_unique()));
+            }
+        }
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let mut page = |start: u64, count: u8| {
+            let mut instruction_data = vec![63];
+            instruction_data.extend_from_slice(&start.to_le_bytes());
+            instruction_data.push(count);
+            process(&program_id, &mut ico, &mut [], &instruction_data)?;
+            Ok::<_, ProgramError>(
+                Vec::<Pubkey>::try_from_slice(&envelope_payload(RETURN_WHITELIST_PAGE)).unwrap(),
+            )
+        };
+
+        let mut seen = vec![];
+        for start in (0..6).step_by(2) {
+            seen.extend(page(start, 2).unwrap());
+        }
+        assert_eq!(seen, listed);
+        assert_eq!(page(5, 2).unwrap(), vec![]);
+        assert_eq!(page(0, MAX_WHITELIST_PAGE as u8).unwrap(), listed);
+        assert_eq!(
+            page(0, MAX_WHITELIST_PAGE as u8 + 1),
+            Err(ProgramError::InvalidInstructionData)
+        );
+    }
+
+    #[test]
+    fn budget_quotes_stop_at_supply_and_caps() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        let mut ico = TestAccount::new(0, 0);
+        let mut buyer = TestAccount::buyer(0);
+        let quote = |ico_state: &ICOAccount, buyer: Option<&Pubkey>, budget: u64| {
+            quote_budget(ico_state, buyer, budget).unwrap();
+            let payload = envelope_payload(RETURN_BUDGET_QUOTE);
+            (u64_at(&payload, 0), u64_at(&payload, 1))
+        };
+
+        set_time(SALE_TIME);
+        assert_eq!(quote(&ico_state, None, 55), (2, 40));
+        assert_eq!(quote(&ico_state, None, 1_000_000), (7_000, 140_000));
+
+        sale_purchase(&mut ico_state, &mut ico, &mut buyer, 20).unwrap();
+        ico_state.hard_cap = 6_400;
+        assert_eq!(quote(&ico_state, None, 1_000_000), (300, 6_000));
+        ico_state.max_contribution = 1_000;
+        assert_eq!(quote(&ico_state, Some(&buyer.key), 1_000_000), (30, 600));
+        assert_eq!(quote(&ico_state, None, 1_000_000), (50, 1_000));
+
+        // The first sale purchase rolled the presale pool over, so quote the
+        // presale allocation on a fresh state.
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        register_whitelisted(&mut ico_state, &buyer.key, 25, false).unwrap();
+        set_time(PRE_SALE_TIME);
+        assert_eq!(quote(&ico_state, Some(&buyer.key), 1_000_000), (25, 250));
+        assert_eq!(quote(&ico_state, None, 1_000_000), (3_000, 30_000));
+        set_time(ENDED_TIME);
+        assert_eq!(
+            quote_budget(&ico_state, None, 1_000),
+            Err(ICOError::SaleNotActive.into())
+        );
+    }
+
+    #[test]
+    fn burning_unsold_tokens_can_shrink_the_total_supply() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        let mut buyer = TestAccount::buyer(0);
+        sale_purchase(
+            &mut ico_state,
+            &mut TestAccount::new(0, 0),
+            &mut buyer,
+            6_000,
+        )
+        .unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let burn = |amount: u64, shrink_supply: bool| {
+            let mut instruction_data = vec![65];
+            instruction_data.extend_from_slice(&amount.to_le_bytes());
+            instruction_data.push(shrink_supply as u8);
+            instruction_data
+        };
+
+        set_time(ENDED_TIME);
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin],
+            &burn(1_000, false),
+        )
+        .unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.total_supply, ADMIN_SUPPLY);
+        assert_eq!(balance_of(&ico_state, &admin.key), 3_000);
+
+        process(&program_id, &mut ico, &mut [&mut admin], &burn(2_000, true)).unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.total_supply, 8_000);
+        assert_eq!(balance_of(&ico_state, &admin.key), 1_000);
+
+        // Only 2_000 tokens are outside circulation, sold tokens can't go.
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut admin], &burn(2_001, true)),
+            Err(ICOError::SupplyBelowCirculating.into())
+        );
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.total_supply, 8_000);
+        assert_eq!(ico_state.circulating_supply().unwrap(), 6_000);
+    }
+
+    #[test]
+    fn guardian_can_pause_but_not_mint() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut guardian = TestAccount::signer();
+        let mut impostor = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut guardian], &[66, 1]),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut guardian],
+            &[67],
+        )
+        .unwrap();
+        assert_eq!(read_state(&ico).guardian, guardian.key);
+
+        process(&program_id, &mut ico, &mut [&mut guardian], &[66, 1]).unwrap();
+        assert!(read_state(&ico).paused);
+        process(&program_id, &mut ico, &mut [&mut guardian], &[66, 0]).unwrap();
+        assert!(!read_state(&ico).paused);
+
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut guardian, &mut impostor],
+                &mint_instruction(100, None)
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(
+            process(
+                &program_id,
+                &mut ico,
+                &mut [&mut guardian, &mut impostor],
+                &[67]
+            ),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+        assert_eq!(read_state(&ico).total_supply, ADMIN_SUPPLY);
+    }
+
+    #[test]
+    fn oversubscribed_allocations_are_prorated_to_the_pool() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.pre_sale_supply = 100;
+        let mut capped: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        capped.sort();
+        for address in &capped {
+            register_whitelisted(&mut ico_state, address, 50, false).unwrap();
+        }
+        let uncapped = Pubkey::new_unique();
+        register_whitelisted(&mut ico_state, &uncapped, 0, false).unwrap();
+        let mut unlisted = unlisted_entry(&Pubkey::new_unique());
+        unlisted.allocation = 500;
+        ico_state.pre_sale_account.push(unlisted);
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        process(&program_id, &mut ico, &mut [&mut admin], &[68]).unwrap();
+        let mut ico_state = read_state(&ico);
+        let allocations: Vec<u64> = ico_state
+            .pre_sale_account
+            .iter()
+            .map(|pre_sale_account| pre_sale_account.allocation)
+            .collect();
+        // Equal remainders, so the lowest address gets the leftover token.
+        let lowest = ico_state
+            .pre_sale_account
+            .iter()
+            .position(|pre_sale_account| pre_sale_account.address == capped[0])
+            .unwrap();
+        let mut expected = vec![33, 33, 33, 0, 500];
+        expected[lowest] = 34;
+        assert_eq!(allocations, expected);
+
+        // Resubmitted proofs can only shrink the prorated allocation.
+        record_whitelist_allocation(&mut ico_state, &capped[1], 50).unwrap();
+        record_whitelist_allocation(&mut ico_state, &capped[2], 20).unwrap();
+        let allocation = |ico_state: &ICOAccount, address: &Pubkey| {
+            ico_state
+                .pre_sale_account
+                .iter()
+                .find(|pre_sale_account| &pre_sale_account.address == address)
+                .unwrap()
+                .allocation
+        };
+        assert_eq!(allocation(&ico_state, &capped[1]), 33);
+        assert_eq!(allocation(&ico_state, &capped[2]), 20);
+    }
+
+    #[test]
+    fn allocation_prorated_to_zero_loses_its_spot_for_good() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+        ico_state.pre_sale_supply = 10;
+        let mut ico = TestAccount::new(0, 0);
+        let large = Pubkey::new_unique();
+        let mut small = TestAccount::buyer(0);
+        register_whitelisted(&mut ico_state, &large, 1_000, false).unwrap();
+        register_whitelisted(&mut ico_state, &small.key, 1, false).unwrap();
+
+        prorate_allocations(&mut ico_state, &admin.info()).unwrap();
+        assert_eq!(ico_state.pre_sale_account[0].allocation, 10);
+        let entry = &ico_state.pre_sale_account[1];
+        assert!(!entry.whitelist_account);
+        assert!(entry.prorated_out);
+        assert_eq!((entry.allocation, entry.whitelist_expiry), (0, 0));
+
+        // Neither moving the expiry nor whitelisting again restores the spot.
+        set_whitelist_expiry(&mut ico_state, &admin.info(), &small.key, 1_000).unwrap();
+        whitelist_account(&mut ico_state, &small.key).unwrap();
+        assert!(!ico_state.is_whitelisted(&small.key));
+        assert_eq!(
+            presale_purchase(&mut ico_state, &mut ico, &mut small, 1),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        let leaf = merkle_leaf(&large, 1_000);
+        set_whitelist_root(
+            &mut ico_state,
+            &admin.info(),
+            merkle_root(merkle_leaf(&small.key, 1), leaf),
+        )
+        .unwrap();
+        let proof = MerkleWhitelistProof {
+            allocation: 1,
+            proof: vec![leaf],
+        };
+        assert_eq!(
+            merkle_purchase(&mut ico_state, &mut ico, &mut small, 1, Some(proof)),
+            Err(ICOError::NotWhitelisted.into())
+        );
+        assert!(logged("Whitelist spot was prorated away"));
+    }
+
+    #[test]
+    fn transfer_tax_goes_to_the_treasury() {
+        setup();
+        let admin = Pubkey::new_unique();
+        let mut ico_state = test_state(&admin);
+        ico_state.transfers_enabled = true;
+        ico_state.transfer_tax_bps = 250;
+        ico_state.treasury = Pubkey::new_unique();
+        let mut sender = TestAccount::signer();
+        let recipient = Pubkey::new_unique();
+        mint_tokens(&mut ico_state, &sender.key, 2_000).unwrap();
+
+        transfer_tokens(&mut ico_state, &sender.info(), &recipient, 1_000).unwrap();
+        assert_eq!(balance_of(&ico_state, &sender.key), 1_000);
+        assert_eq!(balance_of(&ico_state, &recipient), 975);
+        assert_eq!(balance_of(&ico_state, &ico_state.treasury), 25);
+
+        // 2.5% of 39 rounds down to nothing.
+        transfer_tokens(&mut ico_state, &sender.info(), &recipient, 39).unwrap();
+        assert_eq!(balance_of(&ico_state, &recipient), 1_014);
+        assert_eq!(balance_of(&ico_state, &ico_state.treasury), 25);
+
+        // Without a treasury the admin collects the tax.
+        ico_state.treasury = Pubkey::default();
+        transfer_tokens(&mut ico_state, &sender.info(), &recipient, 400).unwrap();
+        assert_eq!(balance_of(&ico_state, &recipient), 1_404);
+        assert_eq!(balance_of(&ico_state, &admin), ADMIN_SUPPLY + 10);
+        let total: u64 = ico_state.balance.iter().map(|(_, amount)| amount).sum();
+        assert_eq!(total, ico_state.total_supply);
+    }
+
+    #[test]
+    fn active_limit_follows_the_phase() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.pre_sale_limit = 300;
+        ico_state.sale_limit = 700;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        for (time, limit) in [(PRE_SALE_TIME, 300), (SALE_TIME, 700)] {
+            set_time(time);
+            process(&program_id, &mut ico, &mut [], &[69]).unwrap();
+            assert_eq!(u64_at(&envelope_payload(RETURN_ACTIVE_LIMIT), 0), limit);
+        }
+        set_time(ENDED_TIME);
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [], &[69]),
+            Err(ICOError::SaleNotActive.into())
+        );
+    }
+
+    #[test]
+    fn expired_whitelist_spot_cannot_buy_until_extended() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut buyer = TestAccount::buyer(10);
+        let mut ico_state = test_state(&admin.key);
+        register_whitelisted(&mut ico_state, &buyer.key, 0, false).unwrap();
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let expire_at = |expiry: u64| {
+            let mut instruction_data = vec![70];
+            instruction_data.extend_from_slice(&expiry.to_le_bytes());
+            instruction_data
+        };
+
+        set_time(PRE_SALE_TIME);
+        let expiry = PRE_SALE_TIME as u64;
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut buyer],
+            &expire_at(expiry),
+        )
+        .unwrap();
+        process(&program_id, &mut ico, &mut [&mut buyer], &[2]).unwrap();
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut buyer],
+            &expire_at(expiry - 1),
+        )
+        .unwrap();
+        assert_eq!(
+            process(&program_id, &mut ico, &mut [&mut buyer], &[2]),
+            Err(ICOError::NotWhitelisted.into())
+        );
+        assert!(logged("Whitelist spot has expired"));
+
+        process(
+            &program_id,
+            &mut ico,
+            &mut [&mut admin, &mut buyer],
+            &expire_at(expiry + 10),
+        )
+        .unwrap();
+        process(&program_id, &mut ico, &mut [&mut buyer], &[2]).unwrap();
+        assert_eq!(balance_of(&read_state(&ico), &buyer.key), 20);
+    }
+
+    #[test]
+    fn reconcile_reports_and_repairs_balances_that_diverge_from_supply() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let holder = Pubkey::new_unique();
+        let admin_key = admin.key;
+        let mut ico_state = test_state(&admin_key);
+        // Tokens credited without being minted.
+        *ico_state.balance_entry(&holder) = 150;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        let mut reconcile = |ico: &mut TestAccount, confirm: u8| {
+            process(&program_id, ico, &mut [&mut admin], &[71, confirm]).unwrap();
+            let payload = envelope_payload(RETURN_BALANCE_RECONCILIATION);
+            (u64_at(&payload, 0), u64_at(&payload, 1))
+        };
+
+        assert_eq!(reconcile(&mut ico, 0), (ADMIN_SUPPLY + 150, ADMIN_SUPPLY));
+        assert_eq!(balance_of(&read_state(&ico), &admin_key), ADMIN_SUPPLY);
+
+        assert_eq!(reconcile(&mut ico, 1), (ADMIN_SUPPLY + 150, ADMIN_SUPPLY));
+        assert!(logged("Admin balance reconciled from 10000 to 9850"));
+        let ico_state = read_state(&ico);
+        assert_eq!(balance_of(&ico_state, &admin_key), ADMIN_SUPPLY - 150);
+        assert_eq!(balance_of(&ico_state, &holder), 150);
+        assert_eq!(reconcile(&mut ico, 0), (ADMIN_SUPPLY, ADMIN_SUPPLY));
+
+        // Supply minted without a balance is handed to the admin.
+        let mut ico_state = read_state(&ico);
+        ico_state.total_supply += 500;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        assert_eq!(reconcile(&mut ico, 1), (ADMIN_SUPPLY, ADMIN_SUPPLY + 500));
+        assert_eq!(
+            balance_of(&read_state(&ico), &admin_key),
+            ADMIN_SUPPLY + 350
+        );
+        assert_eq!(
+            reconcile(&mut ico, 0),
+            (ADMIN_SUPPLY + 500, ADMIN_SUPPLY + 500)
+        );
+    }
+
+    #[test]
+    fn reconcile_leaves_burned_unsold_tokens_burned() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut admin = TestAccount::signer();
+        let mut ico = ico_account(&program_id, &mut test_state(&admin.key));
+
+        set_time(ENDED_TIME);
+        let mut burn = vec![65];
+        burn.extend_from_slice(&1_000u64.to_le_bytes());
+        burn.push(0);
+        process(&program_id, &mut ico, &mut [&mut admin], &burn).unwrap();
+        process(&program_id, &mut ico, &mut [&mut admin], &[71, 1]).unwrap();
+        assert!(!logged("Admin balance reconciled"));
+        let payload = envelope_payload(RETURN_BALANCE_RECONCILIATION);
+        assert_eq!(u64_at(&payload, 0), u64_at(&payload, 1));
+
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.burned_unsold, 1_000);
+        assert_eq!(ico_state.total_supply, ADMIN_SUPPLY);
+        assert_eq!(balance_of(&ico_state, &admin.key), ADMIN_SUPPLY - 1_000);
+        health_check(&ico_state).unwrap();
+        assert_eq!(return_data(), vec![0]);
+    }
+
+    #[test]
+    fn preview_reservations_are_not_counted_as_purchases() {
+        setup();
+        let program_id = Pubkey::new_unique();
+        let mut buyer = TestAccount::buyer(20);
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        register_whitelisted(&mut ico_state, &buyer.key, 50, false).unwrap();
+        ico_state.preview_mode = true;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+
+        set_time(PRE_SALE_TIME);
+        process(&program_id, &mut ico, &mut [&mut buyer], &[2]).unwrap();
+        let ico_state = read_state(&ico);
+        assert_eq!(ico_state.preview_reservations, vec![(buyer.key, 20)]);
+        assert_eq!(ico_state.total_purchases, 0);
+
+        let mut ico_state = read_state(&ico);
+        ico_state.preview_mode = false;
+        let mut ico = ico_account(&program_id, &mut ico_state);
+        process(&program_id, &mut ico, &mut [&mut buyer], &[2]).unwrap();
+        assert_eq!(read_state(&ico).total_purchases, 1);
+    }
+
+    #[test]
+    fn payer_reveals_its_commitment_when_buying_for_a_beneficiary() {
+        setup();
+        let mut ico_state = test_state(&Pubkey::new_unique());
+        ico_state.commit_deadline = 40;
+        let mut ico = TestAccount::new(0, 0);
+        let mut payer = TestAccount::buyer(30);
+        let mut beneficiary = TestAccount::new(0, 0);
+        register_whitelisted(&mut ico_state, &beneficiary.key, 0, false).unwrap();
+        let nonce = [5; 32];
+        set_time(10);
+        commit_purchase(
+            &mut ico_state,
+            &payer.info(),
+            hashv(&[&30u64.to_le_bytes(), &nonce]).to_bytes(),
+        )
+        .unwrap();
+
+        set_time(PRE_SALE_TIME);
+        let ico_info = ico.info();
+        let accounts = [payer.info(), beneficiary.info()];
+        pre_sale(
+            &mut ico_state,
+            &ico_info,
+            &mut accounts.iter(),
+            None,
+            Some(nonce),
+        )
+        .unwrap();
+        assert!(ico_state.purchase_commitments.is_empty());
+        assert_eq!(balance_of(&ico_state, &beneficiary.key), 30);
+        assert_eq!(balance_of(&ico_state, &payer.key), 0);
+    }
+
+    #[test]
+    fn sale_extensions_stop_at_the_round_cap() {
+        setup();
+        let mut admin = TestAccount::signer();
+        let mut ico_state = test_state(&admin.key);
+
+        set_time(SALE_TIME);
+        for new_end in (1..MAX_ROUNDS).map(|round| 200 + round * 100) {
+            extend_sale(&mut ico_state, &admin.info(), new_end).unwrap();
+        }
+        assert_eq!(ico_state.sale_extensions, MAX_ROUNDS - 1);
+        assert_eq!(ico_state.sale_end_time, 200 + (MAX_ROUNDS - 1) * 100);
+        assert_eq!(
+            extend_sale(&mut ico_state, &admin.info(), 1_000),
+            Err(ICOError::MaxRoundsReached.into())
+        );
+        assert_eq!(ico_state.sale_end_time, 500);
+    }
 }